@@ -0,0 +1,63 @@
+//! Atomic write batches, see `KvDB::write`.
+
+use crate::record::RecordType;
+
+/// A group of puts, deletes and merges that is applied all at once or not at all.
+///
+/// The operations are written to the log together, between a begin and a commit marker,
+/// and recovery drops a batch whose commit marker never made it to disk.
+///
+/// ```no_run
+/// use kv_db::{batch::WriteBatch, db::KvDB, store::DbTraits};
+///
+/// let mut db = KvDB::open("tmp").expect("Should open the database");
+///
+/// let mut batch = WriteBatch::new();
+/// batch.put(b"from", b"90").put(b"to", b"110").delete(b"pending");
+///
+/// db.write(batch).expect("Should write the batch");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<(RecordType, Vec<u8>, Vec<u8>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts or updates a key-value pair when the batch is written.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops
+            .push((RecordType::Put, key.to_vec(), value.to_vec()));
+        self
+    }
+
+    /// Deletes a key when the batch is written.
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops
+            .push((RecordType::Delete, key.to_vec(), Vec::new()));
+        self
+    }
+
+    /// Merges an operand into a key when the batch is written, see `KvDB::merge`.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> &mut Self {
+        self.ops
+            .push((RecordType::Merge, key.to_vec(), operand.to_vec()));
+        self
+    }
+
+    /// Number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
@@ -0,0 +1,626 @@
+//! Background compaction, which rewrites the live records of immutable log files and drops the stale ones.
+//!
+//! Compaction runs in three phases so the store is only locked briefly:
+//! 1. Plan (store locked): pick the input files and take the location of every live record in them.
+//! 2. Copy (store unlocked): copy those records into new log files, each capped at the max file size,
+//!    along with their hints.
+//!    Merge operands are folded into the value they add up to on the way.
+//!    Reads and writes carry on meanwhile.
+//! 3. Install (store locked): point the index at the copies, unless a key was written again during the copy,
+//!    then swap the new files in for the inputs. The inputs are deleted after the store is unlocked again.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::KvError,
+    helper::nanos_to_system_time,
+    hint::{HintEntry, write_hint},
+    index::IndexEntry,
+    merge::MergeOperator,
+    reader::ReaderCache,
+    record::{NO_EXPIRY, Record, RecordType},
+    store::{KvStore, delete_logs, resolve},
+    wal::LogWriter,
+};
+
+/// How many records are copied between checks for a shutdown.
+const STOP_CHECK_INTERVAL: usize = 256;
+/// Read handles kept open while copying, input files are read mostly in order.
+const COPY_OPEN_FILES: usize = 4;
+
+/// The live records to copy, taken from the index while the store was locked.
+#[derive(Debug)]
+pub struct CompactionPlan {
+    /// The database directory.
+    pub dir_path: PathBuf,
+    /// Ids of the log files being compacted.
+    pub inputs: Vec<u64>,
+    /// Total size of the inputs.
+    pub input_bytes: u64,
+    /// Size (in bytes) at which an output file is full.
+    pub max_file_size: u64,
+    /// Every live key in the inputs, with the records to fold into its copy, oldest first.
+    /// That is its newest record, and the operands it builds on if it is a merge.
+    pub entries: Vec<(Vec<u8>, Vec<IndexEntry>)>,
+    /// Expired values in the inputs, which are not copied and leave the index on install.
+    pub expired: Vec<(Vec<u8>, IndexEntry)>,
+    /// Folds merge operands into values.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Tombstones to carry over as `(key, timestamp, seq)`, see `KvStore::plan_compaction`.
+    pub tombstones: Vec<(Vec<u8>, i64, u64)>,
+}
+
+/// The copied records, ready to be installed.
+#[derive(Debug)]
+pub struct CompactionOutput {
+    /// The output files, in the order they were written.
+    pub files: Vec<OutputFile>,
+}
+
+/// A log file written by compaction.
+///
+/// It has a fresh id, and only becomes live once the manifest lists it.
+#[derive(Debug)]
+pub struct OutputFile {
+    pub file_id: u64,
+    pub path: PathBuf,
+    pub size: u64,
+    /// For each record, the index entries it was folded from and its entry in this file.
+    /// Carried over tombstones have no old location.
+    pub records: Vec<(Vec<IndexEntry>, HintEntry)>,
+}
+
+/// What a compaction run did, see `KvDB::compact`.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionReport {
+    pub files_read: usize,
+    pub files_written: usize,
+    /// Size of the inputs minus the size of the outputs.
+    pub bytes_reclaimed: u64,
+    pub live_keys_copied: usize,
+    pub duration: Duration,
+}
+
+/// Runs compactions one at a time, and remembers the report of the last one.
+#[derive(Debug, Default)]
+pub struct Compactor {
+    running: Mutex<()>,
+    last: Mutex<Option<CompactionReport>>,
+}
+
+impl Compactor {
+    /// Runs one compaction, waiting for any compaction already in progress to finish first.
+    ///
+    /// A `full` compaction rewrites every immutable log, otherwise only the ones with enough garbage.
+    /// Returns `None` if there was nothing to compact or the store shut down mid-way.
+    pub fn run(
+        &self,
+        store: &Mutex<KvStore>,
+        full: bool,
+    ) -> Result<Option<CompactionReport>, KvError> {
+        let _running = self
+            .running
+            .lock()
+            .expect("Compaction lock should not be poisoned");
+
+        let report = compact(store, full)?;
+
+        if let Some(report) = &report {
+            *self
+                .last
+                .lock()
+                .expect("Report lock should not be poisoned") = Some(report.clone());
+        }
+
+        Ok(report)
+    }
+
+    /// The report of the last compaction, manual or in the background.
+    pub fn last(&self) -> Option<CompactionReport> {
+        self.last
+            .lock()
+            .expect("Report lock should not be poisoned")
+            .clone()
+    }
+}
+
+/// Spawns the compaction worker.
+///
+/// The worker wakes up when something is sent on `trigger` or every `interval`,
+/// and compacts when the store reports enough stale data. It exits once the store is shut down.
+pub fn spawn_worker(
+    store: Arc<Mutex<KvStore>>,
+    compactor: Arc<Compactor>,
+    trigger: Receiver<()>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            if let Err(RecvTimeoutError::Disconnected) = trigger.recv_timeout(interval) {
+                break;
+            }
+
+            let due = {
+                let store = store.lock().expect("Store lock should not be poisoned");
+
+                if !store.running {
+                    break;
+                }
+
+                store.check_compaction()
+            };
+
+            if due && let Err(err) = compactor.run(&store, false) {
+                println!("[Warn]: Compaction failed: {:?}", err);
+            }
+        }
+    })
+}
+
+/// Runs one compaction, only locking the store to plan and to install it.
+fn compact(store: &Mutex<KvStore>, full: bool) -> Result<Option<CompactionReport>, KvError> {
+    let lock = || store.lock().expect("Store lock should not be poisoned");
+    let started = Instant::now();
+
+    let plan = match lock().plan_compaction(full)? {
+        Some(plan) => plan,
+        None => return Ok(None),
+    };
+
+    println!("[Info]: Starting compaction of {} files", plan.inputs.len());
+
+    let mut written = Vec::new();
+
+    let result = match copy_live_records(
+        &plan,
+        &mut written,
+        || lock().allocate_log(),
+        || !lock().running,
+    ) {
+        Ok(Some(output)) => {
+            let output_bytes: u64 = output.files.iter().map(|file| file.size).sum();
+            let live_keys_copied = output
+                .files
+                .iter()
+                .flat_map(|file| &file.records)
+                .filter(|(folded, _)| !folded.is_empty())
+                .count();
+
+            let files_read = plan.inputs.len();
+            let files_written = output.files.len();
+            let bytes_reclaimed = plan.input_bytes.saturating_sub(output_bytes);
+
+            let installed = lock().install_compaction(plan, output);
+
+            installed.map(|retired| {
+                delete_logs(&retired);
+
+                Some(CompactionReport {
+                    files_read,
+                    files_written,
+                    bytes_reclaimed,
+                    live_keys_copied,
+                    duration: started.elapsed(),
+                })
+            })
+        }
+        Ok(None) => Ok(None), // shut down mid-way
+        Err(err) => Err(err),
+    };
+
+    // the outputs never made it into the manifest, so they are orphans that would otherwise wait for the next startup
+    if !matches!(result, Ok(Some(_))) {
+        for output_path in &written {
+            discard_output(output_path);
+        }
+    }
+
+    result
+}
+
+/// Deletes the output of a compaction that was not installed, along with its hint.
+fn discard_output(output_path: &Path) {
+    for path in [
+        output_path.with_extension("hint"),
+        output_path.to_path_buf(),
+    ] {
+        if let Err(err) = fs::remove_file(&path)
+            && err.kind() != ErrorKind::NotFound
+        {
+            println!("[Warn]: Failed to delete {}: {:?}", path.display(), err);
+        }
+    }
+}
+
+/// Writes compacted records, moving on to a new log file whenever the current one is full.
+struct OutputWriter<F> {
+    max_file_size: u64,
+    allocate: F,
+    current: Option<(LogWriter, OutputFile)>,
+    files: Vec<OutputFile>,
+}
+
+impl<F: FnMut() -> (u64, PathBuf)> OutputWriter<F> {
+    /// Appends a record and adds its entry to the output file it ended up in.
+    fn append(
+        &mut self,
+        record: Record,
+        folded: Vec<IndexEntry>,
+        written: &mut Vec<PathBuf>,
+    ) -> Result<(), KvError> {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(writer, _)| writer.size() > self.max_file_size)
+        {
+            self.finish()?;
+        }
+
+        let (writer, file) = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let (file_id, path) = (self.allocate)();
+                written.push(path.clone());
+                let writer = LogWriter::create(&path)?;
+
+                self.current.insert((
+                    writer,
+                    OutputFile {
+                        file_id,
+                        path,
+                        size: 0,
+                        records: Vec::new(),
+                    },
+                ))
+            }
+        };
+
+        let mut entry = HintEntry::for_record(&record);
+
+        let (size, offset) = writer.append(record)?;
+        file.size = writer.size();
+
+        entry.offset = offset;
+        entry.size = size as u64; // older records grow when rewritten in the current format
+
+        file.records.push((folded, entry));
+
+        Ok(())
+    }
+
+    /// Flushes the current output file and writes its hint, so installing it takes no more disk work.
+    fn finish(&mut self) -> Result<(), KvError> {
+        if let Some((writer, file)) = self.current.take() {
+            writer.sync()?;
+
+            let hints: Vec<HintEntry> = file
+                .records
+                .iter()
+                .map(|(_, entry)| entry.clone())
+                .collect();
+
+            let hint_path = file.path.with_extension("hint");
+            if let Err(err) = write_hint(&hint_path, file.size, &hints) {
+                println!(
+                    "[Warn]: Failed to write hint file {}: {:?}",
+                    hint_path.display(),
+                    err
+                );
+            }
+
+            self.files.push(file);
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies every record in the plan into new output files, see `CompactionPlan`.
+///
+/// `allocate` hands out a fresh id and path for each output file, and the path of every file created
+/// is added to `written` so the caller can clean up if the compaction is not installed.
+///
+/// Returns `None` if `should_stop` asked to abandon the copy.
+fn copy_live_records(
+    plan: &CompactionPlan,
+    written: &mut Vec<PathBuf>,
+    allocate: impl FnMut() -> (u64, PathBuf),
+    should_stop: impl Fn() -> bool,
+) -> Result<Option<CompactionOutput>, KvError> {
+    let readers = ReaderCache::new(COPY_OPEN_FILES);
+    let mut output = OutputWriter {
+        max_file_size: plan.max_file_size,
+        allocate,
+        current: None,
+        files: Vec::new(),
+    };
+
+    for (key, timestamp, seq) in &plan.tombstones {
+        let record = Record {
+            record_type: RecordType::Delete,
+            timestamp: nanos_to_system_time(*timestamp),
+            expires_at: None,
+            seq: *seq,
+            key,
+            value: &[],
+        };
+
+        output.append(record, Vec::new(), written)?;
+    }
+
+    for (i, (key, records)) in plan.entries.iter().enumerate() {
+        if i % STOP_CHECK_INTERVAL == 0 && should_stop() {
+            return Ok(None);
+        }
+
+        let merge_operator = plan.merge_operator.as_deref();
+
+        // merges are written out as the value they add up to, so later compactions never need their operands
+        let (value, header) = match resolve(&readers, &plan.dir_path, key, records, merge_operator)?
+        {
+            Some(val) => val,
+            None => continue,
+        };
+
+        let record = Record {
+            record_type: RecordType::Put,
+            timestamp: nanos_to_system_time(header.timestamp),
+            expires_at: (header.expires_at != NO_EXPIRY)
+                .then(|| nanos_to_system_time(header.expires_at)),
+            seq: header.seq,
+            key,
+            value: &value,
+        };
+
+        output.append(record, records.clone(), written)?;
+    }
+
+    output.finish()?;
+
+    Ok(Some(CompactionOutput {
+        files: output.files,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread, time::Duration};
+
+    use super::*;
+    use crate::{
+        db::KvDB,
+        manifest::Manifest,
+        merge::U64Add,
+        options::Options,
+        store::{DbTraits, log_path, tests::temp_dir},
+    };
+
+    /// Copies the files of `from` that `to` does not have yet, returning where they went.
+    fn copy_missing(from: &Path, to: &Path) -> Vec<PathBuf> {
+        fs::create_dir_all(to).unwrap();
+
+        let mut copied = Vec::new();
+        for file in fs::read_dir(from).unwrap() {
+            let target = to.join(file.unwrap().file_name());
+            if !target.exists() {
+                fs::copy(from.join(target.file_name().unwrap()), &target).unwrap();
+                copied.push(target);
+            }
+        }
+
+        copied
+    }
+
+    /// Overwrites every key of a store across a few logs and compacts it, returning its directory and a copy of
+    /// it from before the compaction.
+    fn compacted_store(name: &str) -> (PathBuf, PathBuf) {
+        let dir = temp_dir(name);
+        let before = temp_dir(&format!("{}_before", name));
+        let options = Options::builder().max_file_size(256).build();
+
+        let mut db = KvDB::open_with(&dir, options.clone()).unwrap();
+        for round in 0..2u32 {
+            for i in 0..20u32 {
+                db.put(&i.to_le_bytes(), &round.to_le_bytes()).unwrap();
+            }
+        }
+        drop(db);
+        copy_missing(&dir, &before);
+
+        let db = KvDB::open_with(&dir, options).unwrap();
+        assert!(db.compact().unwrap().files_read > 0);
+        drop(db);
+
+        (dir, before)
+    }
+
+    /// Asserts that every key holds the value of the last round `compacted_store` wrote.
+    fn assert_latest_values(dir: &Path) {
+        let db = KvDB::open(dir).unwrap();
+
+        for i in 0..20u32 {
+            assert_eq!(
+                db.get(&i.to_le_bytes()).unwrap(),
+                Some(1u32.to_le_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn unfinished_compaction_output_is_removed() {
+        let (dir, before) = compacted_store("unfinished_compaction");
+
+        // a crash before the manifest switched over leaves the output behind, next to a half-written manifest
+        let orphans = copy_missing(&dir, &before);
+        fs::write(before.join("MANIFEST.tmp"), "kv_db manifest v2\nseq").unwrap();
+        assert!(!orphans.is_empty());
+
+        assert_latest_values(&before);
+        assert!(orphans.iter().all(|orphan| !orphan.exists()));
+    }
+
+    #[test]
+    fn replaced_compaction_inputs_are_removed() {
+        let (dir, before) = compacted_store("replaced_inputs");
+
+        // a crash after the manifest switched over leaves the inputs behind
+        let orphans = copy_missing(&before, &dir);
+        assert!(!orphans.is_empty());
+
+        assert_latest_values(&dir);
+        assert!(orphans.iter().all(|orphan| !orphan.exists()));
+    }
+
+    /// The immutable logs of the store in `dir`, and their total size.
+    fn immutable_logs(dir: &Path) -> (Vec<u64>, u64) {
+        let manifest = Manifest::load(dir).unwrap().unwrap();
+        let files = &manifest.files()[..manifest.files().len() - 1];
+        let size = files
+            .iter()
+            .map(|file_id| fs::metadata(log_path(dir, *file_id)).unwrap().len())
+            .sum();
+
+        (files.to_vec(), size)
+    }
+
+    #[test]
+    fn compaction_report_matches_the_logs() {
+        let dir = temp_dir("compaction_report");
+        let options = Options::builder().max_file_size(256).build();
+        let mut db = KvDB::open_with(&dir, options).unwrap();
+
+        // 46 byte records, so six fit in each log and the last four writes land in the active log
+        for round in 0..2u32 {
+            for i in 0..20u32 {
+                db.put(&i.to_le_bytes(), &round.to_le_bytes()).unwrap();
+            }
+        }
+        let (inputs, input_bytes) = immutable_logs(&dir);
+        assert_eq!(inputs.len(), 6);
+        assert!(db.last_compaction().is_none());
+
+        let report = db.compact().unwrap();
+        let (outputs, output_bytes) = immutable_logs(&dir);
+
+        assert_eq!(report.files_read, inputs.len());
+        assert_eq!(report.files_written, outputs.len());
+        assert!(outputs.iter().all(|output| !inputs.contains(output)));
+        assert_eq!(report.bytes_reclaimed, input_bytes - output_bytes);
+        // keys 16 to 19 were last written to the active log, which is left alone
+        assert_eq!(report.live_keys_copied, 16);
+
+        let last = db.last_compaction().unwrap();
+        assert_eq!(last.files_read, report.files_read);
+        assert_eq!(last.files_written, report.files_written);
+        assert_eq!(last.bytes_reclaimed, report.bytes_reclaimed);
+        assert_eq!(last.live_keys_copied, report.live_keys_copied);
+        assert_eq!(last.duration, report.duration);
+    }
+
+    #[test]
+    fn compacting_nothing_reports_nothing() {
+        let mut db = KvDB::open(temp_dir("empty_compaction")).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        // the only log is the active one
+        let report = db.compact().unwrap();
+        assert_eq!(report.files_read, 0);
+        assert_eq!(report.files_written, 0);
+        assert_eq!(report.bytes_reclaimed, 0);
+        assert_eq!(report.live_keys_copied, 0);
+        assert!(db.last_compaction().is_none());
+    }
+
+    /// Waits for the background worker to finish a compaction, giving up after a couple of seconds.
+    fn wait_for_compaction(db: &KvDB) -> Option<CompactionReport> {
+        let deadline = Instant::now() + Duration::from_secs(2);
+
+        while db.last_compaction().is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        db.last_compaction()
+    }
+
+    #[test]
+    fn expired_values_are_compacted_in_the_background() {
+        let options = Options::builder()
+            .max_file_size(256)
+            .compaction_threshold(0)
+            .compaction_interval(Duration::from_millis(20))
+            .build();
+        let mut db = KvDB::open_with(temp_dir("expired_compaction"), options).unwrap();
+
+        for i in 0..100u32 {
+            db.put_with_ttl(&i.to_le_bytes(), b"value", Duration::from_millis(10))
+                .unwrap();
+        }
+
+        assert!(wait_for_compaction(&db).is_some());
+        assert!(db.stats().keys < 100);
+    }
+
+    #[test]
+    fn deletes_wake_the_worker() {
+        let options = Options::builder()
+            .max_file_size(256)
+            .compaction_threshold(0)
+            .compaction_interval(Duration::from_secs(60))
+            .build();
+        let mut db = KvDB::open_with(temp_dir("delete_compaction"), options).unwrap();
+
+        for i in 0..100u32 {
+            db.put(&i.to_le_bytes(), b"value").unwrap();
+        }
+        assert!(db.last_compaction().is_none());
+
+        // nothing else is written, so only the deletes can wake the worker before its timer
+        for i in 0..100u32 {
+            db.delete(&i.to_le_bytes()).unwrap();
+        }
+
+        assert!(wait_for_compaction(&db).is_some());
+    }
+
+    #[test]
+    fn merge_operands_are_folded_in_the_background() {
+        let dir = temp_dir("merge_compaction");
+        let options = || {
+            Options::builder()
+                .max_file_size(256)
+                .compaction_threshold(0)
+                .compaction_interval(Duration::from_millis(20))
+                .merge_operator(U64Add)
+                .build()
+        };
+
+        let mut db = KvDB::open_with(&dir, options()).unwrap();
+
+        for _ in 0..200 {
+            db.merge(b"counter", &1u64.to_le_bytes()).unwrap();
+        }
+
+        assert!(wait_for_compaction(&db).is_some());
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(200u64.to_le_bytes().to_vec())
+        );
+        drop(db);
+
+        let db = KvDB::open_with(&dir, options()).unwrap();
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(200u64.to_le_bytes().to_vec())
+        );
+    }
+}
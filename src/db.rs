@@ -1,20 +1,51 @@
 use std::{
-    sync::{Arc, Mutex},
+    ops::{Bound, RangeBounds},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
 use crate::{
+    batch::WriteBatch,
+    compaction::{self, CompactionReport, Compactor},
+    entry::{Entry, Metadata},
     error::KvError,
-    store::{DbTraits, KvStore},
+    iter::{self, Iter},
+    options::{Options, SyncPolicy},
+    snapshot::Snapshot,
+    stats::Stats,
+    store::{CasResult, DbTraits, KvStore},
+    transaction::Transaction,
+    wal::GroupCommit,
 };
 
+/// A thread-safe handle to the database.
+///
+/// Cloning is cheap and every clone shares the same store, so writers on different threads
+/// can share fsyncs under `SyncPolicy::Always`. The store is closed when the last clone is dropped.
+#[derive(Clone)]
 pub struct KvDB {
+    inner: Arc<DbInner>,
+}
+
+struct DbInner {
     store: Arc<Mutex<KvStore>>,
+    commit: Arc<GroupCommit>,
+    compactor: Arc<Compactor>,
     compaction_thread: Option<JoinHandle<()>>,
+    flusher: Option<(Sender<()>, JoinHandle<()>)>,
 }
 
-impl Drop for KvDB {
+impl Drop for DbInner {
     fn drop(&mut self) {
+        if let Some((stop, handle)) = self.flusher.take() {
+            drop(stop);
+            handle.join().expect("Flusher thread should not panic");
+        }
+
         self.store
             .lock()
             .expect("Store lock should not be poisoned")
@@ -26,44 +57,471 @@ impl Drop for KvDB {
     }
 }
 
-impl DbTraits for KvDB {
-    fn open(path: impl Into<std::path::PathBuf>) -> Result<Self, KvError> {
-        let store = Arc::new(Mutex::new(KvStore::open(path)?));
+impl KvDB {
+    /// Opens the database like `DbTraits::open`, with the given options.
+    pub fn open_with(
+        path: impl Into<std::path::PathBuf>,
+        options: Options,
+    ) -> Result<Self, KvError> {
+        let read_only = options.read_only;
+        let sync_policy = options.sync_policy;
+        let compaction_interval = options.compaction_interval;
+
+        let mut store = KvStore::open_with(path, options)?;
+        let trigger = store.take_compaction_trigger();
+
+        let store = Arc::new(Mutex::new(store));
+        let commit = Arc::new(GroupCommit::new());
+        let compactor = Arc::new(Compactor::default());
+
+        let compaction_thread = match trigger {
+            Some(trigger) if !read_only => Some(compaction::spawn_worker(
+                Arc::clone(&store),
+                Arc::clone(&compactor),
+                trigger,
+                compaction_interval,
+            )),
+            _ => None,
+        };
+
+        let flusher = match sync_policy {
+            SyncPolicy::Interval(interval) if !read_only => {
+                let (stop, stopped) = mpsc::channel::<()>();
+                let store = Arc::clone(&store);
+                let commit = Arc::clone(&commit);
+
+                let handle = thread::spawn(move || {
+                    // wakes up every interval until the sender is dropped
+                    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                        if let Err(err) = sync(&store, &commit) {
+                            println!("[Warn]: Periodic sync failed: {:?}", err);
+                        }
+                    }
+                });
+
+                Some((stop, handle))
+            }
+            _ => None,
+        };
 
         Ok(Self {
-            store,
-            compaction_thread: None,
+            inner: Arc::new(DbInner {
+                store,
+                commit,
+                compactor,
+                compaction_thread,
+                flusher,
+            }),
         })
     }
 
-    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), KvError> {
-        let mut store = self
+    /// Flushes every write made so far to disk, whatever the sync policy.
+    pub fn sync(&self) -> Result<(), KvError> {
+        sync(&self.inner.store, &self.inner.commit)
+    }
+
+    /// Compacts every immutable log, whatever its garbage ratio, and waits for it to finish.
+    ///
+    /// Waits for a background compaction in progress first. The active log is left alone.
+    ///
+    /// # Errors
+    /// Returns `KvError::ReadOnly` if the database was opened read-only.
+    pub fn compact(&self) -> Result<CompactionReport, KvError> {
+        let report = self.inner.compactor.run(&self.inner.store, true)?;
+
+        Ok(report.unwrap_or_default())
+    }
+
+    /// The report of the last compaction, manual or in the background.
+    pub fn last_compaction(&self) -> Option<CompactionReport> {
+        self.inner.compactor.last()
+    }
+
+    /// Scans every key and value in key order.
+    ///
+    /// An ordered index (see `IndexType`) serves the keys lazily, the hash index sorts them all up front.
+    /// Values are read a batch at a time either way.
+    pub fn iter(&self) -> Iter {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Scans the keys in `range` and their values in key order, e.g. `db.range(b"a".as_slice()..b"c")`.
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Iter {
+        let (start, end) = iter::bounds(range);
+
+        Iter::new(Arc::clone(&self.inner.store), start, end, None)
+    }
+
+    /// Scans the keys starting with `prefix` and their values in key order.
+    pub fn prefix(&self, prefix: &[u8]) -> Iter {
+        Iter::new(
+            Arc::clone(&self.inner.store),
+            Bound::Included(prefix.to_vec()),
+            iter::prefix_end(prefix),
+            None,
+        )
+    }
+
+    /// Takes a consistent, read-only view of the database as it is now, see `Snapshot`.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(Arc::clone(&self.inner.store))
+    }
+
+    /// Retrieves the value of `key` along with its timestamp, sequence number and expiry.
+    pub fn get_entry(&self, key: &[u8]) -> Result<Option<Entry>, KvError> {
+        self.lock().get_entry(key)
+    }
+
+    /// The timestamp, sequence number, expiry and size of `key`, without reading its value.
+    ///
+    /// The sequence number changes whenever the key is written, so it can tell whether a cached value is stale.
+    pub fn metadata(&self, key: &[u8]) -> Option<Metadata> {
+        self.lock().metadata(key)
+    }
+
+    /// Approximate size (in bytes) of the in-memory index.
+    pub fn memory_usage(&self) -> usize {
+        self.lock().memory_usage()
+    }
+
+    /// Live and dead bytes of every log file, see `Stats`.
+    pub fn stats(&self) -> Stats {
+        self.lock().stats()
+    }
+
+    /// Inserts or updates a key-value pair that reads as missing once `ttl` has passed.
+    ///
+    /// A `ttl` too long to represent, such as `Duration::MAX`, never expires.
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), KvError> {
+        self.write_with(|store| store.put_with_ttl_deferred(key, value, ttl))
+    }
+
+    /// Applies `operand` to the value of `key` with the merge operator from the options, see `merge::MergeOperator`.
+    ///
+    /// Nothing is read: the operand is appended to the log, and folded into the value on `get`
+    /// and for good by compaction. Useful for counters and lists that would otherwise take a get and a put.
+    ///
+    /// # Errors
+    /// Returns `KvError::NoMergeOperator` if the database was opened without a merge operator.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<(), KvError> {
+        self.write_with(|store| store.merge_deferred(key, operand))
+    }
+
+    /// Writes `new` to `key`, or deletes it if `new` is `None`, but only if its current value is `expected`,
+    /// with `None` meaning the key is missing.
+    ///
+    /// The check and the write happen under the store lock, so of several writers racing on the same
+    /// expected value only one succeeds. The others get `CasResult::Failed` with the value that won.
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasResult, KvError> {
+        self.write_with(|store| store.compare_and_swap_deferred(key, expected, new))
+    }
+
+    /// Inserts a key-value pair unless the key already exists, see `KvDB::compare_and_swap`.
+    pub fn put_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<CasResult, KvError> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Deletes a key only if its current value is `expected`, see `KvDB::compare_and_swap`.
+    pub fn delete_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<CasResult, KvError> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// Applies every put and delete in `batch` atomically, see `WriteBatch`.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<(), KvError> {
+        self.write_with(|store| store.write_deferred(&batch))
+    }
+
+    /// Starts an optimistic transaction that reads from a snapshot taken now, see `Transaction`.
+    pub fn begin_transaction(&self) -> Transaction {
+        Transaction::new(self.clone(), self.snapshot())
+    }
+
+    /// Writes `batch` like `KvDB::write`, unless one of `keys` was written after `snapshot` was taken.
+    ///
+    /// # Errors
+    /// Returns `KvError::Conflict` with the first changed key, in which case nothing is written.
+    pub(crate) fn write_unchanged<'a>(
+        &self,
+        batch: &WriteBatch,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+        snapshot: &Snapshot,
+    ) -> Result<(), KvError> {
+        self.write_with(|store| {
+            if let Some(key) = keys
+                .into_iter()
+                .find(|key| store.changed_since(key, snapshot.seq()))
+            {
+                return Err(KvError::Conflict { key: key.to_vec() });
+            }
+
+            store.write_deferred(batch)
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, KvStore> {
+        self.inner
             .store
             .lock()
-            .expect("Store lock should not be poisoned");
-
-        if store.check_compaction() {
-            let tx = store.sender.clone();
-            thread::spawn(move || {
-                tx.send(()).expect("Receiver should not be dropped")
-                // KvStore::check_compaction(store_clone);
-            });
+            .expect("Store lock should not be poisoned")
+    }
+
+    /// Runs `write` on the store, then waits for it to be durable if the sync policy asks for it.
+    ///
+    /// Wakes up the compaction worker first if there is enough garbage, compaction itself never runs
+    /// on the caller's thread.
+    fn write_with<T>(
+        &self,
+        write: impl FnOnce(&mut KvStore) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let (result, ticket) = {
+            let mut store = self.lock();
+
+            // a wake-up already pending is enough, so writes above the threshold do not queue up more
+            if store.check_compaction() {
+                let _ = store.sender.try_send(());
+            }
+
+            let before = store.written();
+            let result = write(&mut store)?;
+
+            // nothing to wait for if nothing was written, e.g. a failed compare-and-swap
+            let ticket = (store.written() > before && store.sync_due()).then(|| store.written());
+
+            (result, ticket)
+        };
+
+        // the fsync runs outside the store lock, so readers and other writers are not held up by the disk
+        if let Some(ticket) = ticket {
+            self.inner
+                .commit
+                .wait(ticket, || flush(&self.inner.store))?;
         }
 
-        store.put(key, value)
+        Ok(result)
+    }
+}
+
+/// Flushes every write made so far, sharing the fsync with any concurrent writers.
+fn sync(store: &Mutex<KvStore>, commit: &GroupCommit) -> Result<(), KvError> {
+    let ticket = store
+        .lock()
+        .expect("Store lock should not be poisoned")
+        .written();
+
+    commit.wait(ticket, || flush(store))
+}
+
+/// Flushes the active log without holding the store lock while the disk works,
+/// so other writers can keep appending (and join the next flush).
+fn flush(store: &Mutex<KvStore>) -> Result<u64, KvError> {
+    let (file, written) = store
+        .lock()
+        .expect("Store lock should not be poisoned")
+        .sync_handle();
+
+    if let Some(file) = file {
+        file.sync_all()?;
+    }
+
+    // restarts the count for `SyncPolicy::EveryN` and `SyncPolicy::Interval`
+    store
+        .lock()
+        .expect("Store lock should not be poisoned")
+        .mark_synced(written);
+
+    Ok(written)
+}
+
+impl DbTraits for KvDB {
+    fn open(path: impl Into<std::path::PathBuf>) -> Result<Self, KvError> {
+        Self::open_with(path, Options::default())
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), KvError> {
+        self.write_with(|store| store.put_deferred(key, value))
     }
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
-        self.store
-            .lock()
-            .expect("Store lock should not be poisoned")
-            .get(key)
+        self.lock().get(key)
     }
 
     fn delete(&mut self, key: &[u8]) -> Result<(), KvError> {
-        self.store
-            .lock()
-            .expect("Store lock should not be poisoned")
-            .delete(key)
+        self.write_with(|store| store.delete_deferred(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{merge::U64Add, store::tests::temp_dir};
+
+    #[test]
+    fn racing_writers_apply_exactly_once() {
+        let db = KvDB::open(temp_dir("cas_race")).unwrap();
+
+        // eight writers swap `expected` for a value of their own, returning the one that won
+        let race = |expected: Option<u8>, base: u8| {
+            let handles: Vec<_> = (0..8u8)
+                .map(|id| {
+                    let mut db = db.clone();
+                    thread::spawn(move || {
+                        let result = match expected {
+                            None => db.put_if_absent(b"lock", &[base + id]),
+                            Some(expected) => {
+                                db.compare_and_swap(b"lock", Some(&[expected]), Some(&[base + id]))
+                            }
+                        };
+                        (base + id, result.unwrap())
+                    })
+                })
+                .collect();
+
+            let results: Vec<_> = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect();
+
+            let winners: Vec<u8> = results
+                .iter()
+                .filter(|(_, result)| result.is_applied())
+                .map(|(value, _)| *value)
+                .collect();
+            assert_eq!(winners.len(), 1);
+
+            // every loser saw the value that won
+            let won = Some(vec![winners[0]]);
+            for (_, result) in &results {
+                assert!(result.is_applied() || *result == CasResult::Failed(won.clone()));
+            }
+            assert_eq!(db.get(b"lock").unwrap(), won);
+
+            winners[0]
+        };
+
+        let first = race(None, 0);
+        race(Some(first), 100);
+    }
+
+    #[test]
+    fn failed_swap_carries_the_current_value() {
+        let mut db = KvDB::open(temp_dir("cas_failed")).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        assert_eq!(
+            db.compare_and_swap(b"a", Some(b"2"), Some(b"3")).unwrap(),
+            CasResult::Failed(Some(b"1".to_vec()))
+        );
+        assert_eq!(
+            db.put_if_absent(b"a", b"3").unwrap(),
+            CasResult::Failed(Some(b"1".to_vec()))
+        );
+        assert_eq!(
+            db.delete_if_equals(b"missing", b"1").unwrap(),
+            CasResult::Failed(None)
+        );
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        assert_eq!(db.delete_if_equals(b"a", b"1").unwrap(), CasResult::Applied);
+        assert_eq!(db.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn expired_key_counts_as_missing() {
+        let mut db = KvDB::open(temp_dir("cas_expired")).unwrap();
+        db.put_with_ttl(b"a", b"1", Duration::from_millis(10))
+            .unwrap();
+        thread::sleep(Duration::from_millis(30));
+
+        assert_eq!(
+            db.compare_and_swap(b"a", Some(b"1"), Some(b"2")).unwrap(),
+            CasResult::Failed(None)
+        );
+        assert_eq!(db.put_if_absent(b"a", b"2").unwrap(), CasResult::Applied);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn merged_key_is_compared_by_its_folded_value() {
+        let options = Options::builder().merge_operator(U64Add).build();
+        let mut db = KvDB::open_with(temp_dir("cas_merged"), options).unwrap();
+        let count = |n: u64| n.to_le_bytes();
+
+        db.merge(b"a", &count(1)).unwrap();
+        db.merge(b"a", &count(1)).unwrap();
+
+        assert_eq!(
+            db.compare_and_swap(b"a", Some(&count(1)), Some(&count(10)))
+                .unwrap(),
+            CasResult::Failed(Some(count(2).to_vec()))
+        );
+        assert_eq!(
+            db.compare_and_swap(b"a", Some(&count(2)), Some(&count(10)))
+                .unwrap(),
+            CasResult::Applied
+        );
+
+        // a merge on top of the swapped value builds on it
+        db.merge(b"a", &count(1)).unwrap();
+        assert_eq!(
+            db.delete_if_equals(b"a", &count(11)).unwrap(),
+            CasResult::Applied
+        );
+        assert_eq!(db.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn concurrent_writers_are_durable_under_always() {
+        let dir = temp_dir("group_commit");
+        let db = KvDB::open(&dir).unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|writer| {
+                let mut db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        db.put(format!("{writer}-{i}").as_bytes(), b"v").unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // every write was flushed before it returned, whichever writer led the fsync
+        assert_eq!(db.lock().written(), 400);
+        assert_eq!(db.lock().unsynced(), 0);
+
+        drop(db);
+        let db = KvDB::open(&dir).unwrap();
+        assert_eq!(db.iter().count(), 400);
+    }
+
+    #[test]
+    fn every_n_counts_from_the_last_sync() {
+        let options = Options::builder()
+            .sync_policy(SyncPolicy::EveryN(3))
+            .build();
+        let mut db = KvDB::open_with(temp_dir("every_n"), options).unwrap();
+
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"1").unwrap();
+        assert_eq!(db.lock().unsynced(), 2);
+
+        db.sync().unwrap();
+        assert_eq!(db.lock().unsynced(), 0);
+
+        db.put(b"a", b"2").unwrap();
+        db.put(b"b", b"2").unwrap();
+        assert_eq!(db.lock().unsynced(), 2);
+
+        db.delete(b"a").unwrap();
+        assert_eq!(db.lock().unsynced(), 0);
     }
 }
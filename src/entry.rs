@@ -0,0 +1,126 @@
+//! What the store knows about a key besides its value, see `KvDB::get_entry` and `KvDB::metadata`.
+
+use std::time::SystemTime;
+
+use crate::{
+    helper::nanos_to_system_time,
+    index::IndexEntry,
+    record::{Header, NO_EXPIRY},
+};
+
+/// A value along with the metadata of the record it was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Vec<u8>,
+    /// When the value was written.
+    pub timestamp: SystemTime,
+    /// The sequence number of the write, which changes whenever the key is written again.
+    /// `0` for records from before sequence numbers.
+    pub seq: u64,
+    /// When the value expires, `None` if it never does.
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Entry {
+    pub(crate) fn new(value: Vec<u8>, header: &Header) -> Self {
+        Self {
+            value,
+            timestamp: nanos_to_system_time(header.timestamp),
+            seq: header.seq,
+            expires_at: expiry(header.expires_at),
+        }
+    }
+}
+
+/// The metadata of a key, answered from the index without reading the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// When the value was written.
+    pub timestamp: SystemTime,
+    /// The sequence number of the write, see `Entry::seq`.
+    pub seq: u64,
+    /// When the value expires, `None` if it never does.
+    pub expires_at: Option<SystemTime>,
+    /// Size of the record on disk, header and key included.
+    pub size: u64,
+}
+
+impl From<IndexEntry> for Metadata {
+    fn from(entry: IndexEntry) -> Self {
+        Self {
+            timestamp: nanos_to_system_time(entry.timestamp),
+            seq: entry.seq,
+            expires_at: expiry(entry.expires_at),
+            size: entry.size as u64,
+        }
+    }
+}
+
+fn expiry(expires_at: i64) -> Option<SystemTime> {
+    (expires_at != NO_EXPIRY).then(|| nanos_to_system_time(expires_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        options::Options,
+        record::HEADER_SIZE,
+        store::{DbTraits, KvStore, tests::temp_dir},
+    };
+
+    #[test]
+    fn overwrites_get_a_new_seq() {
+        let mut store = KvStore::open(temp_dir("entry_seq")).unwrap();
+        store.put(b"a", b"1").unwrap();
+        store.put(b"b", b"1").unwrap();
+
+        let first = store.get_entry(b"a").unwrap().unwrap();
+        let metadata = store.metadata(b"a").unwrap();
+        assert_eq!(first.value, b"1");
+        assert_eq!(metadata.seq, first.seq);
+        assert_eq!(metadata.timestamp, first.timestamp);
+        assert_eq!(metadata.size, (HEADER_SIZE + 2) as u64);
+        assert_ne!(store.metadata(b"b").unwrap().seq, first.seq);
+
+        store.put(b"a", b"22").unwrap();
+
+        let second = store.get_entry(b"a").unwrap().unwrap();
+        assert_eq!(second.value, b"22");
+        assert!(second.seq > first.seq);
+        assert!(second.timestamp >= first.timestamp);
+        assert_eq!(store.metadata(b"a").unwrap().seq, second.seq);
+        assert_eq!(store.metadata(b"a").unwrap().size, (HEADER_SIZE + 3) as u64);
+
+        store.delete(b"a").unwrap();
+        assert_eq!(store.get_entry(b"a").unwrap(), None);
+        assert_eq!(store.metadata(b"a"), None);
+    }
+
+    #[test]
+    fn expiry_round_trips() {
+        let dir = temp_dir("entry_expiry");
+        // each record fills a log, so after reopening the first two are read from hints
+        let options = || Options::builder().max_file_size(1).build();
+        let ttl = Duration::from_secs(3600);
+
+        let mut store = KvStore::open_with(&dir, options()).unwrap();
+        store.put_with_ttl(b"a", b"1", ttl).unwrap();
+        store.put(b"b", b"1").unwrap();
+        store.put_with_ttl(b"c", b"1", ttl).unwrap();
+
+        let written = store.get_entry(b"a").unwrap().unwrap();
+        assert_eq!(written.expires_at, Some(written.timestamp + ttl));
+        assert_eq!(store.metadata(b"b").unwrap().expires_at, None);
+        let metadata = [b"a", b"b", b"c"].map(|key| store.metadata(key).unwrap());
+        drop(store);
+
+        let store = KvStore::open_with(&dir, options()).unwrap();
+        assert_eq!(store.get_entry(b"a").unwrap().unwrap(), written);
+        assert_eq!(
+            [b"a", b"b", b"c"].map(|key| store.metadata(key).unwrap()),
+            metadata
+        );
+    }
+}
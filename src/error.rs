@@ -1,8 +1,23 @@
-use std::{fmt::Debug, io::Error as IoError};
+use std::{fmt::Debug, io::Error as IoError, path::PathBuf};
 
 pub enum KvError {
     Io(IoError),
     InvalidDir,
+    /// The directory already holds a database and `error_if_exists` was set.
+    AlreadyExists,
+    /// A write was attempted on a store opened read-only.
+    ReadOnly,
+    /// A record failed its checksum or could not be decoded.
+    Corruption {
+        file: PathBuf,
+        offset: u64,
+    },
+    /// A transaction read or wrote a key that another writer changed after the transaction began.
+    Conflict {
+        key: Vec<u8>,
+    },
+    /// A merge was written or read without a merge operator in the options.
+    NoMergeOperator,
 }
 
 impl From<IoError> for KvError {
@@ -15,7 +30,25 @@ impl Debug for KvError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             KvError::InvalidDir => write!(f, "Invalid directory"),
+            KvError::AlreadyExists => write!(f, "Database already exists"),
+            KvError::ReadOnly => write!(f, "Database is read-only"),
+            KvError::NoMergeOperator => write!(f, "No merge operator configured"),
             KvError::Io(err) => write!(f, "IO error: {}", err),
+            KvError::Corruption { file, offset } => {
+                write!(
+                    f,
+                    "Corrupted record in {} at offset {}",
+                    file.display(),
+                    offset
+                )
+            }
+            KvError::Conflict { key } => {
+                write!(
+                    f,
+                    "Transaction conflict on key {}",
+                    String::from_utf8_lossy(key)
+                )
+            }
         }
     }
 }
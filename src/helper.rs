@@ -1,10 +1,75 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub fn system_time_to_bytes(sys_time: &SystemTime) -> [u8; 8] {
-    let duration = sys_time
+    system_time_to_nanos(sys_time).to_le_bytes()
+}
+
+/// Nanoseconds since the epoch, as stored in record headers.
+///
+/// Times past what 64 bits of nanoseconds can hold (in 2262) saturate to `i64::MAX`.
+pub fn system_time_to_nanos(sys_time: &SystemTime) -> i64 {
+    let nanos = sys_time
         .duration_since(UNIX_EPOCH)
         .expect("System time should not be earlier than UNIX_EPOCH")
-        .as_secs() as i64;
+        .as_nanos();
+
+    i64::try_from(nanos).unwrap_or(i64::MAX)
+}
+
+/// The inverse of `system_time_to_nanos`.
+pub fn nanos_to_system_time(nanos: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos as u64)
+}
+
+/// Lookup table for the IEEE CRC32 polynomial (reversed `0xEDB88320`).
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+/// Incremental CRC32 (IEEE) hasher, so a checksum can be computed over several buffers without copying them together.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(mut self, bytes: &[u8]) -> Self {
+        for byte in bytes {
+            self.0 = CRC32_TABLE[((self.0 ^ *byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+
+        self
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
 
-    duration.to_le_bytes()
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
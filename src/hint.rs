@@ -0,0 +1,228 @@
+//! Hint files, which let recovery rebuild the in-memory index without reading whole log files.
+//!
+//! Every immutable `N.log` can have an `N.hint` next to it, holding the key, location and timestamp
+//! of each record in the log, but not the values.
+//!
+//! # Hint Format
+//! - File header: (magic: 4 bytes, log_size: 8 bytes)
+//! - Entries: (checksum: 4 bytes, record_type: 1 byte, timestamp: 8 bytes, expires_at: 8 bytes, seq: 8 bytes, offset: 8 bytes, size: 8 bytes, key_size: 4 bytes, key: n bytes)
+//!
+//! `log_size` is the size of the log the hint was built from, so a hint that no longer matches its log is ignored.
+//! The checksum of an entry covers all of its other bytes.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    error::KvError,
+    helper::{Crc32, system_time_to_nanos},
+    record::{NO_EXPIRY, Record, RecordType},
+};
+
+/// Magic bytes that start every hint file.
+const HINT_MAGIC: &[u8; 4] = b"KVH3";
+/// Size of the hint file header in bytes.
+const FILE_HEADER_SIZE: usize = 4 + 8;
+/// Size of an entry header (everything but the key) in bytes.
+const ENTRY_HEADER_SIZE: usize = 4 + 1 + 8 + 8 + 8 + 8 + 8 + 4;
+
+/// The location of a single record in a log file.
+#[derive(Debug, Clone)]
+pub struct HintEntry {
+    /// The type of operation.
+    pub record_type: RecordType,
+    /// Nanoseconds since UNIX_EPOCH at which the operation was performed.
+    pub timestamp: i64,
+    /// Nanoseconds since UNIX_EPOCH at which the value expires, `NO_EXPIRY` if it never does.
+    pub expires_at: i64,
+    /// The sequence number of the write.
+    pub seq: u64,
+    /// The offset of the record in the log file.
+    pub offset: u64,
+    /// The size of the record on disk.
+    pub size: u64,
+    /// The key affected by the operation.
+    pub key: Vec<u8>,
+}
+
+impl HintEntry {
+    /// The entry of a record about to be appended, its offset and size are filled in once it is written.
+    pub fn for_record(record: &Record) -> Self {
+        Self {
+            record_type: record.record_type,
+            timestamp: system_time_to_nanos(&record.timestamp),
+            expires_at: record
+                .expires_at
+                .as_ref()
+                .map_or(NO_EXPIRY, system_time_to_nanos),
+            seq: record.seq,
+            offset: 0,
+            size: 0,
+            key: record.key.to_vec(),
+        }
+    }
+}
+
+/// Writes a hint file for a log of `log_size` bytes.
+///
+/// The hint is written to a temporary file and renamed into place, so a crash never leaves a partial hint behind.
+pub fn write_hint(path: &Path, log_size: u64, entries: &[HintEntry]) -> Result<(), KvError> {
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+
+    writer.write_all(HINT_MAGIC)?;
+    writer.write_all(&log_size.to_le_bytes())?;
+
+    for entry in entries {
+        let mut header = [0u8; ENTRY_HEADER_SIZE];
+        header[4] = entry.record_type as u8;
+        header[5..13].copy_from_slice(&entry.timestamp.to_le_bytes());
+        header[13..21].copy_from_slice(&entry.expires_at.to_le_bytes());
+        header[21..29].copy_from_slice(&entry.seq.to_le_bytes());
+        header[29..37].copy_from_slice(&entry.offset.to_le_bytes());
+        header[37..45].copy_from_slice(&entry.size.to_le_bytes());
+        header[45..49].copy_from_slice(&(entry.key.len() as u32).to_le_bytes());
+
+        let checksum = Crc32::new()
+            .update(&header[4..])
+            .update(&entry.key)
+            .finish();
+        header[..4].copy_from_slice(&checksum.to_le_bytes());
+
+        writer.write_all(&header)?;
+        writer.write_all(&entry.key)?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+
+    fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Loads the hint file at `path` for a log of `log_size` bytes.
+///
+/// Returns `None` if there is no hint file, or if it is corrupted or does not match the log,
+/// in which case the caller should scan the log instead.
+pub fn read_hint(path: &Path, log_size: u64) -> Result<Option<Vec<HintEntry>>, KvError> {
+    let mut buf = Vec::new();
+
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let entries = decode_hint(&buf, log_size);
+
+    if entries.is_none() {
+        println!(
+            "[Warn]: Ignoring invalid hint file {}, scanning its log instead",
+            path.display()
+        );
+    }
+
+    Ok(entries)
+}
+
+/// Decodes the contents of a hint file, returning `None` if anything does not check out.
+fn decode_hint(buf: &[u8], log_size: u64) -> Option<Vec<HintEntry>> {
+    if buf.len() < FILE_HEADER_SIZE || &buf[..4] != HINT_MAGIC {
+        return None;
+    }
+
+    if u64::from_le_bytes(buf[4..12].try_into().ok()?) != log_size {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = FILE_HEADER_SIZE;
+
+    while pos < buf.len() {
+        let header = buf.get(pos..pos + ENTRY_HEADER_SIZE)?;
+        let key_len = u32::from_le_bytes(header[45..49].try_into().ok()?) as usize;
+        let key = buf.get(pos + ENTRY_HEADER_SIZE..pos + ENTRY_HEADER_SIZE + key_len)?;
+
+        let checksum = u32::from_le_bytes(header[..4].try_into().ok()?);
+        if Crc32::new().update(&header[4..]).update(key).finish() != checksum {
+            return None;
+        }
+
+        let offset = u64::from_le_bytes(header[29..37].try_into().ok()?);
+        let size = u64::from_le_bytes(header[37..45].try_into().ok()?);
+
+        // an entry pointing outside the log can not be right
+        if offset.checked_add(size)? > log_size {
+            return None;
+        }
+
+        entries.push(HintEntry {
+            record_type: RecordType::try_from(header[4]).ok()?,
+            timestamp: i64::from_le_bytes(header[5..13].try_into().ok()?),
+            expires_at: i64::from_le_bytes(header[13..21].try_into().ok()?),
+            seq: u64::from_le_bytes(header[21..29].try_into().ok()?),
+            offset,
+            size,
+            key: key.to_vec(),
+        });
+
+        pos += ENTRY_HEADER_SIZE + key_len;
+    }
+
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::temp_dir;
+
+    fn entry(key: &[u8], offset: u64) -> HintEntry {
+        HintEntry {
+            record_type: RecordType::Put,
+            timestamp: 1,
+            expires_at: NO_EXPIRY,
+            seq: 7,
+            offset,
+            size: 40,
+            key: key.to_vec(),
+        }
+    }
+
+    #[test]
+    fn hint_round_trips() {
+        let dir = temp_dir("hint_round_trip");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("0.hint");
+
+        let entries = [entry(b"a", 0), entry(b"bc", 40)];
+        write_hint(&path, 80, &entries).unwrap();
+
+        let read = read_hint(&path, 80).unwrap().unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", entries));
+        assert!(read_hint(&path, 81).unwrap().is_none());
+    }
+
+    #[test]
+    fn older_hint_formats_are_ignored() {
+        let dir = temp_dir("hint_old_magic");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("0.hint");
+
+        write_hint(&path, 40, &[entry(b"a", 0)]).unwrap();
+
+        for magic in [b"KVH1", b"KVH2"] {
+            let mut bytes = fs::read(&path).unwrap();
+            bytes[..4].copy_from_slice(magic);
+            fs::write(dir.join("old.hint"), bytes).unwrap();
+
+            assert!(read_hint(&dir.join("old.hint"), 40).unwrap().is_none());
+        }
+    }
+}
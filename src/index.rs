@@ -0,0 +1,389 @@
+//! The in-memory index, which maps every live key to the location of its newest record.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    ops::{Bound, RangeBounds},
+};
+
+use crossbeam_skiplist::SkipMap;
+
+use crate::{options::IndexType, record::is_expired};
+
+/// Where the newest record of a key lives.
+///
+/// There is one per key, so it is kept small: the log path is resolved from the file id when reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub file_id: u32,
+    pub offset: u64,
+    /// Size of the whole record on disk.
+    pub size: u32,
+    /// When the record was written, in nanoseconds since the epoch.
+    pub timestamp: i64,
+    /// When the value expires, in nanoseconds since the epoch, or `NO_EXPIRY`.
+    pub expires_at: i64,
+    /// The sequence number of the write.
+    pub seq: u64,
+}
+
+impl IndexEntry {
+    pub fn new(
+        file_id: u64,
+        offset: u64,
+        size: usize,
+        timestamp: i64,
+        expires_at: i64,
+        seq: u64,
+    ) -> Self {
+        Self {
+            file_id: u32::try_from(file_id).expect("File ids should fit in 32 bits"),
+            offset,
+            size: u32::try_from(size).expect("Record sizes should fit in 32 bits"),
+            timestamp,
+            expires_at,
+            seq,
+        }
+    }
+
+    /// Whether the value is expired at `now`, in nanoseconds since the epoch.
+    pub fn is_expired(&self, now: i64) -> bool {
+        is_expired(self.expires_at, now)
+    }
+
+    /// The file id as used everywhere else.
+    pub fn file_id(&self) -> u64 {
+        self.file_id as u64
+    }
+}
+
+/// The in-memory index, see `IndexType` for the implementations to choose from.
+pub trait Indexer: Debug + Send + Sync {
+    fn get(&self, key: &[u8]) -> Option<IndexEntry>;
+    /// Points `key` at `entry`, returning the entry it replaces.
+    fn put(&mut self, key: Vec<u8>, entry: IndexEntry) -> Option<IndexEntry>;
+    /// Removes `key`, returning its entry.
+    fn delete(&mut self, key: &[u8]) -> Option<IndexEntry>;
+    /// Every key with its entry. Ordered indexes return them in key order, the hash index in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, IndexEntry)> + '_>;
+    /// The keys between `start` and `end` with their entries, in key order.
+    ///
+    /// Lazy for ordered indexes, the hash index has to collect and sort the matching keys first.
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Vec<u8>, IndexEntry)> + 'a>;
+    /// Whether `range` is lazy.
+    fn is_ordered(&self) -> bool;
+    fn len(&self) -> usize;
+    /// Approximate size (in bytes) of the index, including the keys it owns.
+    fn memory_usage(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Creates an empty index of the given type.
+pub fn new_indexer(index_type: IndexType) -> Box<dyn Indexer> {
+    match index_type {
+        IndexType::Hash => Box::new(HashIndex::default()),
+        IndexType::BTree => Box::new(BTreeIndex::default()),
+        IndexType::SkipList => Box::new(SkipListIndex::default()),
+    }
+}
+
+/// Size of a key and its entry, wherever the index keeps them.
+const SLOT_SIZE: usize = size_of::<(Vec<u8>, IndexEntry)>();
+
+/// An unordered index backed by a `HashMap`, the fastest for point lookups.
+#[derive(Debug, Default)]
+pub struct HashIndex {
+    map: HashMap<Vec<u8>, IndexEntry>,
+}
+
+impl Indexer for HashIndex {
+    fn get(&self, key: &[u8]) -> Option<IndexEntry> {
+        self.map.get(key).copied()
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: IndexEntry) -> Option<IndexEntry> {
+        self.map.insert(key, entry)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<IndexEntry> {
+        self.map.remove(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, IndexEntry)> + '_> {
+        Box::new(self.map.iter().map(|(key, entry)| (key.clone(), *entry)))
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Vec<u8>, IndexEntry)> + 'a> {
+        let mut entries: Vec<_> = self
+            .map
+            .iter()
+            .filter(|(key, _)| (start, end).contains(key.as_slice()))
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect();
+
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        Box::new(entries.into_iter())
+    }
+
+    fn is_ordered(&self) -> bool {
+        false
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        // every slot holds a key and an entry, plus one control byte
+        let table = self.map.capacity() * (SLOT_SIZE + 1);
+        let keys: usize = self.map.keys().map(Vec::capacity).sum();
+
+        table + keys
+    }
+}
+
+/// An ordered index backed by a `BTreeMap`.
+#[derive(Debug, Default)]
+pub struct BTreeIndex {
+    map: BTreeMap<Vec<u8>, IndexEntry>,
+}
+
+impl Indexer for BTreeIndex {
+    fn get(&self, key: &[u8]) -> Option<IndexEntry> {
+        self.map.get(key).copied()
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: IndexEntry) -> Option<IndexEntry> {
+        self.map.insert(key, entry)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<IndexEntry> {
+        self.map.remove(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, IndexEntry)> + '_> {
+        Box::new(self.map.iter().map(|(key, entry)| (key.clone(), *entry)))
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Vec<u8>, IndexEntry)> + 'a> {
+        // `BTreeMap::range` panics on these
+        if is_empty_range(start, end) {
+            return Box::new(std::iter::empty());
+        }
+
+        Box::new(
+            self.map
+                .range::<[u8], _>((start, end))
+                .map(|(key, entry)| (key.clone(), *entry)),
+        )
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        // nodes are about two thirds full on average
+        let nodes = self.map.len() * SLOT_SIZE * 3 / 2;
+        let keys: usize = self.map.keys().map(Vec::capacity).sum();
+
+        nodes + keys
+    }
+}
+
+/// An ordered index backed by a lock-free skiplist.
+#[derive(Debug, Default)]
+pub struct SkipListIndex {
+    map: SkipMap<Vec<u8>, IndexEntry>,
+}
+
+impl Indexer for SkipListIndex {
+    fn get(&self, key: &[u8]) -> Option<IndexEntry> {
+        self.map.get(key).map(|entry| *entry.value())
+    }
+
+    fn put(&mut self, key: Vec<u8>, entry: IndexEntry) -> Option<IndexEntry> {
+        let replaced = self.get(&key);
+        self.map.insert(key, entry);
+
+        replaced
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<IndexEntry> {
+        self.map.remove(key).map(|entry| *entry.value())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, IndexEntry)> + '_> {
+        Box::new(
+            self.map
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value())),
+        )
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Vec<u8>, IndexEntry)> + 'a> {
+        // the bounds only live as long as this call, so they are copied into the iterator
+        let bounds = (start.map(<[u8]>::to_vec), end.map(<[u8]>::to_vec));
+
+        Box::new(
+            self.map
+                .range::<Vec<u8>, _>(bounds)
+                .map(|entry| (entry.key().clone(), *entry.value())),
+        )
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        // each node also carries a reference count and two tower pointers on average
+        let nodes = self.map.len() * (SLOT_SIZE + 3 * size_of::<usize>());
+        let keys: usize = self.map.iter().map(|entry| entry.key().capacity()).sum();
+
+        nodes + keys
+    }
+}
+
+/// Whether no key can lie between `start` and `end`.
+pub(crate) fn is_empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An index of each type, holding the keys "a" to "e" inserted out of order, each pointing at offset `key - 'a'`.
+    fn indexes() -> Vec<Box<dyn Indexer>> {
+        [IndexType::Hash, IndexType::BTree, IndexType::SkipList]
+            .into_iter()
+            .map(|index_type| {
+                let mut index = new_indexer(index_type);
+                for key in [b"c", b"a", b"e", b"b", b"d"] {
+                    index.put(key.to_vec(), entry(key[0] - b'a'));
+                }
+                index
+            })
+            .collect()
+    }
+
+    fn entry(offset: u8) -> IndexEntry {
+        IndexEntry::new(0, offset as u64, 1, 0, 0, 0)
+    }
+
+    /// The keys of a scan, as a string.
+    fn keys(scan: impl Iterator<Item = (Vec<u8>, IndexEntry)>) -> String {
+        scan.map(|(key, found)| {
+            assert_eq!(found, entry(key[0] - b'a'));
+            key[0] as char
+        })
+        .collect()
+    }
+
+    /// `Indexer::range`, so the bounds can be given as byte string literals.
+    fn range<'a>(
+        index: &'a dyn Indexer,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn DoubleEndedIterator<Item = (Vec<u8>, IndexEntry)> + 'a> {
+        index.range(start, end)
+    }
+
+    #[test]
+    fn ordered_indexes_iterate_in_key_order() {
+        for index in indexes() {
+            let mut all = keys(index.iter()).into_bytes();
+            if !index.is_ordered() {
+                all.sort_unstable();
+            }
+
+            assert_eq!(all, b"abcde", "{index:?}");
+        }
+    }
+
+    #[test]
+    fn ranges_respect_their_bounds() {
+        use Bound::{Excluded, Included, Unbounded};
+
+        for index in indexes() {
+            let index = index.as_ref();
+
+            assert_eq!(keys(range(index, Unbounded, Unbounded)), "abcde");
+            assert_eq!(keys(range(index, Included(b"b"), Included(b"d"))), "bcd");
+            assert_eq!(keys(range(index, Excluded(b"b"), Excluded(b"d"))), "c");
+            assert_eq!(keys(range(index, Excluded(b"b"), Unbounded)), "cde");
+            assert_eq!(keys(range(index, Unbounded, Excluded(b"b"))), "a");
+            // bounds between keys
+            assert_eq!(keys(range(index, Included(b"bb"), Included(b"dd"))), "cd");
+            assert_eq!(
+                keys(range(index, Included(b"b"), Included(b"d")).rev()),
+                "dcb"
+            );
+        }
+    }
+
+    #[test]
+    fn empty_and_inverted_ranges_are_empty() {
+        use Bound::{Excluded, Included};
+
+        for index in indexes() {
+            let index = index.as_ref();
+
+            assert_eq!(keys(range(index, Included(b"d"), Included(b"b"))), "");
+            assert_eq!(keys(range(index, Excluded(b"d"), Excluded(b"b"))), "");
+            assert_eq!(keys(range(index, Excluded(b"c"), Excluded(b"c"))), "");
+            assert_eq!(keys(range(index, Included(b"c"), Excluded(b"c"))), "");
+            assert_eq!(keys(range(index, Excluded(b"c"), Included(b"c"))), "");
+            assert_eq!(keys(range(index, Included(b"c"), Included(b"c"))), "c");
+        }
+    }
+
+    #[test]
+    fn put_and_delete_return_the_old_entry() {
+        for mut index in indexes() {
+            assert_eq!(index.put(b"a".to_vec(), entry(9)), Some(entry(0)));
+            assert_eq!(index.get(b"a"), Some(entry(9)));
+            assert_eq!(index.put(b"f".to_vec(), entry(5)), None);
+            assert_eq!(index.len(), 6);
+
+            assert_eq!(index.delete(b"a"), Some(entry(9)));
+            assert_eq!(index.delete(b"a"), None);
+            assert_eq!(index.get(b"a"), None);
+            assert_eq!(index.len(), 5);
+        }
+    }
+}
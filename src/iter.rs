@@ -0,0 +1,326 @@
+//! Ordered scans over the database, see `KvDB::iter`.
+//!
+//! Scans fetch keys from the index in small batches, locking the store only while a batch is taken,
+//! so a long scan never holds up writers. The hash index has no order to resume from, so its keys in range
+//! are sorted once when the scan starts and their values read a batch at a time.
+//! Keys written during a scan may or may not show up.
+
+use std::{
+    collections::VecDeque,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::KvError,
+    snapshot::SnapshotGuard,
+    store::{KvStore, SnapshotPoint},
+};
+
+/// How many keys a scan takes from the index at once.
+const BATCH_SIZE: usize = 128;
+
+/// A key and its value.
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// Where a scan is up to.
+struct Cursor {
+    store: Arc<Mutex<KvStore>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+    snapshot: Option<Arc<SnapshotGuard>>, // keeps the snapshot alive for as long as the scan
+    sorted: Option<VecDeque<Vec<u8>>>,    // keys of an unordered index left to scan, in key order
+    done: bool,
+}
+
+impl Cursor {
+    /// Takes the next batch of keys and hands them to `f` while the store is still locked.
+    ///
+    /// Returns `None` once the scan is finished. A batch can come back empty if none of its keys are visible.
+    fn next_batch<T>(&mut self, f: impl FnOnce(&KvStore, Vec<Vec<u8>>) -> T) -> Option<T> {
+        if self.done {
+            return None;
+        }
+
+        let snapshot = self.snapshot();
+
+        let store = self
+            .store
+            .lock()
+            .expect("Store lock should not be poisoned");
+
+        let start = self.start.as_ref().map(Vec::as_slice);
+        let end = self.end.as_ref().map(Vec::as_slice);
+
+        let mut keys = match store.has_ordered_index() {
+            true => store.scan_keys(start, end, self.reverse, BATCH_SIZE, snapshot),
+            false => {
+                let sorted = self.sorted.get_or_insert_with(|| {
+                    store
+                        .scan_keys(start, end, false, usize::MAX, snapshot)
+                        .into()
+                });
+                let take = BATCH_SIZE.min(sorted.len());
+
+                match self.reverse {
+                    true => sorted.drain(sorted.len() - take..).rev().collect(),
+                    false => sorted.drain(..take).collect(),
+                }
+            }
+        };
+
+        // the next batch picks up right after the last key
+        match (keys.last(), self.reverse) {
+            (None, _) => {
+                self.done = true;
+                return None;
+            }
+            (Some(last), false) => self.start = Bound::Excluded(last.clone()),
+            (Some(last), true) => self.end = Bound::Excluded(last.clone()),
+        }
+
+        // expired keys, and keys written after the snapshot was taken
+        keys.retain(|key| store.entry_at(key, snapshot).is_some());
+
+        Some(f(&store, keys))
+    }
+
+    /// The snapshot being scanned, if any.
+    fn snapshot(&self) -> Option<SnapshotPoint> {
+        self.snapshot.as_ref().map(|snapshot| snapshot.point)
+    }
+}
+
+/// A scan over `(key, value)` pairs in key order. Values are read from the logs as the scan gets to them.
+pub struct Iter {
+    cursor: Cursor,
+    buffer: VecDeque<Result<KeyValue, KvError>>,
+}
+
+impl Iter {
+    pub(crate) fn new(
+        store: Arc<Mutex<KvStore>>,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        snapshot: Option<Arc<SnapshotGuard>>,
+    ) -> Self {
+        Self {
+            cursor: Cursor {
+                store,
+                start,
+                end,
+                reverse: false,
+                snapshot,
+                sorted: None,
+                done: false,
+            },
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Scans from the last key to the first instead.
+    pub fn reverse(mut self) -> Self {
+        self.cursor.reverse = !self.cursor.reverse;
+        self
+    }
+
+    /// Scans the keys only, without reading any values from the logs.
+    pub fn keys(self) -> Keys {
+        Keys {
+            cursor: self.cursor,
+            buffer: VecDeque::new(),
+        }
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<KeyValue, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let snapshot = self.cursor.snapshot();
+
+        while self.buffer.is_empty() {
+            self.buffer = self.cursor.next_batch(|store, keys| {
+                keys.into_iter()
+                    .filter_map(|key| match store.get_at(&key, snapshot) {
+                        Ok(value) => value.map(|value| Ok((key, value))),
+                        Err(err) => Some(Err(err)),
+                    })
+                    .collect()
+            })?;
+        }
+
+        let item = self.buffer.pop_front()?;
+
+        // a scan stops at the first error
+        if item.is_err() {
+            self.cursor.done = true;
+            self.buffer.clear();
+        }
+
+        Some(item)
+    }
+}
+
+/// A scan over keys in key order, served from the index alone.
+pub struct Keys {
+    cursor: Cursor,
+    buffer: VecDeque<Vec<u8>>,
+}
+
+impl Iterator for Keys {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() {
+            self.buffer = self.cursor.next_batch(|_, keys| keys.into())?;
+        }
+
+        self.buffer.pop_front()
+    }
+}
+
+/// Copies the bounds of `range`, e.g. `b"a".as_slice()..b"c"`.
+pub(crate) fn bounds<K: AsRef<[u8]>>(
+    range: impl RangeBounds<K>,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let bound = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+
+    (bound(range.start_bound()), bound(range.end_bound()))
+}
+
+/// The smallest key above every key starting with `prefix`, if there is one.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+
+    Bound::Unbounded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::KvDB,
+        options::{IndexType, Options},
+        store::{DbTraits, tests::temp_dir},
+    };
+
+    /// Opens a database on each index type, holding the keys `000` to `299`, each with itself as value.
+    fn open_each(name: &str) -> Vec<KvDB> {
+        [IndexType::Hash, IndexType::BTree, IndexType::SkipList]
+            .into_iter()
+            .map(|index_type| {
+                let dir = temp_dir(&format!("{}_{:?}", name, index_type));
+                let options = Options::builder().index_type(index_type).build();
+                let mut db = KvDB::open_with(dir, options).unwrap();
+
+                for key in keys(0..300) {
+                    db.put(&key, &key).unwrap();
+                }
+
+                db
+            })
+            .collect()
+    }
+
+    fn keys(range: std::ops::Range<u32>) -> Vec<Vec<u8>> {
+        range.map(|i| format!("{:03}", i).into_bytes()).collect()
+    }
+
+    fn scanned_keys(iter: Iter) -> Vec<Vec<u8>> {
+        iter.map(|item| {
+            let (key, value) = item.unwrap();
+            assert_eq!(key, value);
+            key
+        })
+        .collect()
+    }
+
+    #[test]
+    fn scans_are_in_key_order() {
+        for db in open_each("scan_order") {
+            let mut reversed = keys(0..300);
+            reversed.reverse();
+
+            assert_eq!(scanned_keys(db.iter()), keys(0..300));
+            assert_eq!(scanned_keys(db.iter().reverse()), reversed);
+            assert_eq!(db.iter().keys().collect::<Vec<_>>(), keys(0..300));
+            assert_eq!(db.iter().reverse().keys().collect::<Vec<_>>(), reversed);
+        }
+    }
+
+    #[test]
+    fn range_scans_respect_their_bounds() {
+        for db in open_each("scan_range") {
+            let (from, to) = (b"010".as_slice(), b"140".as_slice());
+
+            assert_eq!(scanned_keys(db.range(from..to)), keys(10..140));
+            assert_eq!(scanned_keys(db.range(from..=to)), keys(10..141));
+            assert_eq!(
+                scanned_keys(db.range::<&[u8]>((Bound::Excluded(from), Bound::Unbounded))),
+                keys(11..300)
+            );
+            assert_eq!(scanned_keys(db.range(..from)), keys(0..10));
+            assert_eq!(db.range(to..from).count(), 0);
+            assert_eq!(db.range(from..from).count(), 0);
+
+            let mut reversed = keys(10..140);
+            reversed.reverse();
+            assert_eq!(scanned_keys(db.range(from..to).reverse()), reversed);
+        }
+    }
+
+    #[test]
+    fn prefix_scans_stay_within_the_prefix() {
+        for db in open_each("scan_prefix") {
+            let mut reversed = keys(100..200);
+            reversed.reverse();
+
+            assert_eq!(scanned_keys(db.prefix(b"1")), keys(100..200));
+            assert_eq!(scanned_keys(db.prefix(b"1").reverse()), reversed);
+            assert_eq!(db.prefix(b"01").keys().collect::<Vec<_>>(), keys(10..20));
+            assert_eq!(db.prefix(b"4").count(), 0);
+        }
+    }
+
+    #[test]
+    fn values_are_read_as_the_scan_gets_to_them() {
+        for mut db in open_each("scan_lazy") {
+            let mut iter = db.iter();
+            assert!(iter.next().is_some());
+
+            // the scan has not reached these yet, so it sees the changes
+            db.delete(b"299").unwrap();
+            db.put(b"298", b"changed").unwrap();
+
+            let rest: Vec<_> = iter.map(Result::unwrap).collect();
+            assert_eq!(rest.len(), 298);
+            assert_eq!(
+                rest.last().unwrap(),
+                &(b"298".to_vec(), b"changed".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn snapshot_scans_ignore_later_writes() {
+        for mut db in open_each("scan_snapshot") {
+            let snapshot = db.snapshot();
+
+            db.delete(b"000").unwrap();
+            db.put(b"001", b"changed").unwrap();
+            db.put(b"300", b"300").unwrap();
+
+            assert_eq!(scanned_keys(snapshot.iter()), keys(0..300));
+            assert_eq!(scanned_keys(snapshot.prefix(b"00").reverse()).len(), 10);
+        }
+    }
+}
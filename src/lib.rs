@@ -1,6 +1,19 @@
+pub mod batch;
+pub mod compaction;
 pub mod db;
+pub mod entry;
 pub mod error;
 pub mod helper;
+pub mod hint;
+pub mod index;
+pub mod iter;
+pub mod manifest;
+pub mod merge;
+pub mod options;
+pub mod reader;
 pub mod record;
+pub mod snapshot;
+pub mod stats;
 pub mod store;
+pub mod transaction;
 pub mod wal;
@@ -0,0 +1,225 @@
+//! The MANIFEST, which records the log files that make up the database.
+//!
+//! Log files are listed in replay order, oldest first; the last one is the active log.
+//! A log file that exists on disk but is not in the manifest is either the output of a compaction
+//! that never finished or an input of one that did, and is deleted on startup.
+//!
+//! # Format
+//! A text file with a header line, the last sequence number handed out, and one file id per line:
+//! ```text
+//! kv_db manifest v2
+//! seq 42
+//! legacy 1
+//! 0
+//! 3
+//! ```
+//!
+//! The sequence number is a lower bound for the next write, since compaction may drop the records that
+//! carried the highest ones. Version 1 manifests have no `seq` line.
+//!
+//! Logs with an id below `legacy` were written before the database had a manifest, and are the only ones
+//! that may hold legacy records without a checksum. The line is left out if there are none.
+//!
+//! The manifest is never modified in place. A new version is written to `MANIFEST.tmp`, flushed, and renamed over
+//! the old one, so a crash leaves either the old or the new version behind.
+
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::error::KvError;
+
+/// Name of the manifest file inside the database directory.
+pub const MANIFEST_NAME: &str = "MANIFEST";
+/// First line of every manifest.
+const MANIFEST_HEADER: &str = "kv_db manifest v2";
+/// First line of manifests without a sequence number.
+const MANIFEST_V1_HEADER: &str = "kv_db manifest v1";
+/// Prefix of the line holding the last sequence number.
+const SEQ_PREFIX: &str = "seq ";
+/// Prefix of the line holding the first id that can not be a legacy log.
+const LEGACY_PREFIX: &str = "legacy ";
+
+/// The ordered list of live log files.
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    files: Vec<u64>,
+    last_seq: u64,
+    legacy_logs: u64, // logs below this id come from before the manifest
+}
+
+impl Manifest {
+    /// Loads the manifest from `dir_path`, returning `None` if there is none yet.
+    ///
+    /// # Errors
+    /// Returns `KvError::Corruption` if the manifest can not be parsed or lists no files.
+    pub fn load(dir_path: &Path) -> Result<Option<Self>, KvError> {
+        let path = dir_path.join(MANIFEST_NAME);
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let corruption = || KvError::Corruption {
+            file: path.clone(),
+            offset: 0,
+        };
+
+        let mut lines = contents.lines().peekable();
+
+        let last_seq = match lines.next() {
+            Some(MANIFEST_HEADER) => lines
+                .next()
+                .and_then(|line| line.strip_prefix(SEQ_PREFIX))
+                .and_then(|seq| seq.parse().ok())
+                .ok_or_else(corruption)?,
+            Some(MANIFEST_V1_HEADER) => 0,
+            _ => return Err(corruption()),
+        };
+
+        let legacy_logs = match lines.next_if(|line| line.starts_with(LEGACY_PREFIX)) {
+            Some(line) => line[LEGACY_PREFIX.len()..]
+                .parse()
+                .map_err(|_| corruption())?,
+            None => 0,
+        };
+
+        let files = lines
+            .map(|line| line.parse().map_err(|_| corruption()))
+            .collect::<Result<Vec<u64>, KvError>>()?;
+
+        // there is always an active log
+        if files.is_empty() {
+            return Err(corruption());
+        }
+
+        Ok(Some(Self {
+            path,
+            files,
+            last_seq,
+            legacy_logs,
+        }))
+    }
+
+    /// Creates a manifest for `dir_path` listing `files`, without writing it to disk.
+    ///
+    /// Logs with an id below `legacy_logs` were written before the manifest, and may hold legacy records.
+    pub fn new(dir_path: &Path, files: Vec<u64>, legacy_logs: u64) -> Self {
+        Self {
+            path: dir_path.join(MANIFEST_NAME),
+            files,
+            last_seq: 0,
+            legacy_logs,
+        }
+    }
+
+    /// The last sequence number handed out when the manifest was written.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Whether the log `file_id` was written before the manifest, so it may hold legacy records.
+    pub fn is_legacy(&self, file_id: u64) -> bool {
+        file_id < self.legacy_logs
+    }
+
+    /// The live log files, oldest first.
+    pub fn files(&self) -> &[u64] {
+        &self.files
+    }
+
+    /// Whether `file_id` is a live log file.
+    pub fn contains(&self, file_id: u64) -> bool {
+        self.files.contains(&file_id)
+    }
+
+    /// Adds a new active log file and writes the manifest, along with the last sequence number handed out.
+    pub fn push(&mut self, file_id: u64, last_seq: u64) -> Result<(), KvError> {
+        let mut files = self.files.clone();
+        files.push(file_id);
+
+        self.update(files, last_seq)
+    }
+
+    /// Replaces the `inputs` of a compaction with its `outputs` and writes the manifest.
+    ///
+    /// The outputs take the place of the newest input, so records that were newer than the inputs stay newer.
+    pub fn replace(
+        &mut self,
+        inputs: &[u64],
+        outputs: &[u64],
+        last_seq: u64,
+    ) -> Result<(), KvError> {
+        let position = self
+            .files
+            .iter()
+            .rposition(|file_id| inputs.contains(file_id))
+            .map_or(0, |position| position + 1);
+
+        let mut files = Vec::with_capacity(self.files.len() + outputs.len());
+        files.extend_from_slice(&self.files[..position]);
+        files.extend_from_slice(outputs);
+        files.extend_from_slice(&self.files[position..]);
+        files.retain(|file_id| !inputs.contains(file_id));
+
+        self.update(files, last_seq)
+    }
+
+    /// Writes the manifest to disk.
+    pub fn persist(&self) -> Result<(), KvError> {
+        write_manifest(&self.path, &self.files, self.last_seq, self.legacy_logs)
+    }
+
+    /// Writes `files` to disk, and only takes them on once they are durable.
+    fn update(&mut self, files: Vec<u64>, last_seq: u64) -> Result<(), KvError> {
+        write_manifest(&self.path, &files, last_seq, self.legacy_logs)?;
+        self.files = files;
+        self.last_seq = last_seq;
+
+        Ok(())
+    }
+}
+
+/// Atomically replaces the manifest at `path` with one listing `files`.
+fn write_manifest(
+    path: &Path,
+    files: &[u64],
+    last_seq: u64,
+    legacy_logs: u64,
+) -> Result<(), KvError> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut contents = String::from(MANIFEST_HEADER);
+    contents.push('\n');
+    contents.push_str(SEQ_PREFIX);
+    contents.push_str(&last_seq.to_string());
+    if legacy_logs > 0 {
+        contents.push('\n');
+        contents.push_str(LEGACY_PREFIX);
+        contents.push_str(&legacy_logs.to_string());
+    }
+    for file_id in files {
+        contents.push('\n');
+        contents.push_str(&file_id.to_string());
+    }
+    contents.push('\n');
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, path)?;
+
+    // the rename itself is only durable once the directory is flushed
+    #[cfg(unix)]
+    if let Some(dir_path) = path.parent() {
+        File::open(dir_path)?.sync_all()?;
+    }
+
+    Ok(())
+}
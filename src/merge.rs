@@ -0,0 +1,86 @@
+//! Merge operators, which update a value from an operand without reading it first, see `KvDB::merge`.
+//!
+//! A merge only appends its operand to the log. Operands are folded into the value when the key is read,
+//! and compaction folds them into a single value for good.
+//!
+//! ```no_run
+//! use kv_db::{db::KvDB, merge::U64Add, options::Options, store::DbTraits};
+//!
+//! let options = Options::builder().merge_operator(U64Add).build();
+//! let mut db = KvDB::open_with("tmp", options).expect("Should open the database");
+//!
+//! db.merge(b"visits", &1u64.to_le_bytes()).expect("Should count the visit");
+//! db.merge(b"visits", &1u64.to_le_bytes()).expect("Should count the visit");
+//!
+//! assert_eq!(db.get(b"visits").unwrap(), Some(2u64.to_le_bytes().to_vec()));
+//! ```
+
+use std::fmt::Debug;
+
+/// Combines merge operands with the value of a key.
+///
+/// Operands are applied one at a time, oldest first, so `merge` must be deterministic:
+/// the same value and operand always give the same result, whenever and wherever it runs.
+pub trait MergeOperator: Debug + Send + Sync {
+    /// Applies `operand` to the `existing` value of `key`, `None` if the key is missing, returning the new value.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+/// Adds operands to a counter. Values and operands are `u64`s in little-endian order.
+///
+/// Anything that is not 8 bytes long counts as `0`, and the sum wraps around on overflow.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64Add;
+
+impl MergeOperator for U64Add {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        let sum = existing
+            .map_or(0, decode_u64)
+            .wrapping_add(decode_u64(operand));
+
+        sum.to_le_bytes().to_vec()
+    }
+}
+
+/// Appends operands to the value, e.g. to build up a list.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BytesAppend;
+
+impl MergeOperator for BytesAppend {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        let mut value = existing.unwrap_or_default().to_vec();
+        value.extend_from_slice(operand);
+        value
+    }
+}
+
+/// Keeps the largest of the value and the operands, compared as `u64`s like `U64Add`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64Max;
+
+impl MergeOperator for U64Max {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        let operand = decode_u64(operand);
+        let max = existing.map_or(operand, |value| decode_u64(value).max(operand));
+
+        max.to_le_bytes().to_vec()
+    }
+}
+
+/// Keeps the smallest of the value and the operands, compared as `u64`s like `U64Add`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64Min;
+
+impl MergeOperator for U64Min {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        let operand = decode_u64(operand);
+        let min = existing.map_or(operand, |value| decode_u64(value).min(operand));
+
+        min.to_le_bytes().to_vec()
+    }
+}
+
+/// Decodes a little-endian `u64`, or `0` if `bytes` is not 8 bytes long.
+fn decode_u64(bytes: &[u8]) -> u64 {
+    bytes.try_into().map_or(0, u64::from_le_bytes)
+}
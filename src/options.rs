@@ -0,0 +1,196 @@
+//! Tuning knobs for opening a database.
+//!
+//! ```no_run
+//! use kv_db::{db::KvDB, options::{Options, SyncPolicy}};
+//! use std::time::Duration;
+//!
+//! let options = Options::builder()
+//!     .max_file_size(64 * 1024 * 1024)
+//!     .sync_policy(SyncPolicy::Interval(Duration::from_millis(100)))
+//!     .build();
+//!
+//! let db = KvDB::open_with("tmp", options).expect("Should open the database");
+//! ```
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{merge::MergeOperator, reader::DEFAULT_MAX_OPEN_FILES};
+
+/// The default size (in bytes) at which the active log is rotated (5MB).
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 5 * 1024 * 1024; // 5MB
+/// The default amount (in bytes) of dead data in immutable logs before triggering compaction (10MB).
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 10 * 1024 * 1024; // 10MB
+/// The default share of dead bytes above which a log is compacted (50%).
+pub const DEFAULT_COMPACTION_GARBAGE_RATIO: f64 = 0.5;
+/// How often the compaction worker checks whether compaction is due by default (30s).
+pub const DEFAULT_COMPACTION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When appended records are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Every write is durable before it returns. Concurrent writers share one fsync (group commit).
+    #[default]
+    Always,
+    /// Flush after every `n` records.
+    EveryN(u64),
+    /// Flush at most this long after a write.
+    Interval(Duration),
+    /// Leave flushing to the OS.
+    Never,
+}
+
+/// How recovery treats corrupted records that are not the torn tail of the newest log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    /// Fail to open the store.
+    #[default]
+    Strict,
+    /// Log the corruption, drop the rest of the affected file and keep going.
+    Lenient,
+}
+
+/// Which data structure backs the in-memory index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexType {
+    /// A hash map, the fastest for point lookups but unordered.
+    #[default]
+    Hash,
+    /// A B-tree, ordered by key.
+    BTree,
+    /// A concurrent skiplist, ordered by key.
+    SkipList,
+}
+
+/// Options for opening a database, see `KvDB::open_with`.
+///
+/// Build them with `Options::builder()`; `Options::default()` gives the settings used by `DbTraits::open`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub(crate) max_file_size: u64,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) compaction_garbage_ratio: f64,
+    pub(crate) compaction_interval: Duration,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) recovery_mode: RecoveryMode,
+    pub(crate) index_type: IndexType,
+    pub(crate) max_open_files: usize,
+    pub(crate) create_if_missing: bool,
+    pub(crate) error_if_exists: bool,
+    pub(crate) read_only: bool,
+    pub(crate) merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_garbage_ratio: DEFAULT_COMPACTION_GARBAGE_RATIO,
+            compaction_interval: DEFAULT_COMPACTION_INTERVAL,
+            sync_policy: SyncPolicy::Always,
+            recovery_mode: RecoveryMode::Strict,
+            index_type: IndexType::Hash,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+            merge_operator: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+}
+
+/// Builder for `Options`, starting from the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    /// Size (in bytes) at which the active log is rotated to a new file.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.options.max_file_size = bytes;
+        self
+    }
+
+    /// Size (in bytes) of stale data after which compaction is triggered.
+    ///
+    /// Only dead bytes in logs above the `compaction_garbage_ratio` count.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.options.compaction_threshold = bytes;
+        self
+    }
+
+    /// Share of dead bytes (between 0 and 1) above which an immutable log is compacted.
+    /// Logs below it are left alone, so compaction does not rewrite mostly live data.
+    pub fn compaction_garbage_ratio(mut self, ratio: f64) -> Self {
+        self.options.compaction_garbage_ratio = ratio;
+        self
+    }
+
+    /// How often the background compaction worker checks whether compaction is due,
+    /// on top of being woken up by writes that cross the threshold.
+    pub fn compaction_interval(mut self, interval: Duration) -> Self {
+        self.options.compaction_interval = interval;
+        self
+    }
+
+    /// When writes are flushed to disk.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.options.sync_policy = sync_policy;
+        self
+    }
+
+    /// How recovery treats corrupted records.
+    pub fn recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.options.recovery_mode = recovery_mode;
+        self
+    }
+
+    /// Which data structure backs the in-memory index.
+    pub fn index_type(mut self, index_type: IndexType) -> Self {
+        self.options.index_type = index_type;
+        self
+    }
+
+    /// Maximum number of log files kept open for reading.
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.options.max_open_files = max_open_files;
+        self
+    }
+
+    /// Create the database directory if it doesn't exist (on by default).
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.options.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Fail to open if the directory already holds a database.
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Self {
+        self.options.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Open without ever writing to the directory. Writes fail with `KvError::ReadOnly`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// The operator that folds the operands of `KvDB::merge` into values. Without one, merges fail.
+    ///
+    /// A database holding merge operands must always be opened with the same operator.
+    pub fn merge_operator(mut self, merge_operator: impl MergeOperator + 'static) -> Self {
+        self.options.merge_operator = Some(Arc::new(merge_operator));
+        self
+    }
+
+    pub fn build(self) -> Options {
+        self.options
+    }
+}
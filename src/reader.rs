@@ -0,0 +1,108 @@
+//! A bounded cache of read handles for log files.
+//!
+//! Reads use positional IO (`pread`), so a cached handle has no cursor to share
+//! and the same handle can serve any number of concurrent reads.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::error::KvError;
+
+/// The default number of log files kept open for reading.
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// Open read handles keyed by file id, evicting the least recently used one when full.
+#[derive(Debug)]
+pub struct ReaderCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    handles: HashMap<u64, (Arc<File>, u64)>, // file_id -> (handle, last use)
+    clock: u64,
+}
+
+impl ReaderCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Returns the read handle for `file_id`, opening `path` if it is not cached yet.
+    pub fn get(&self, file_id: u64, path: &Path) -> Result<Arc<File>, KvError> {
+        let mut inner = self
+            .inner
+            .lock()
+            .expect("Reader cache lock should not be poisoned");
+
+        inner.clock += 1;
+        let now = inner.clock;
+
+        if let Some((file, last_use)) = inner.handles.get_mut(&file_id) {
+            *last_use = now;
+            return Ok(Arc::clone(file));
+        }
+
+        if inner.handles.len() >= self.capacity {
+            let oldest = inner
+                .handles
+                .iter()
+                .min_by_key(|(_, (_, last_use))| *last_use)
+                .map(|(file_id, _)| *file_id);
+
+            if let Some(oldest) = oldest {
+                inner.handles.remove(&oldest);
+            }
+        }
+
+        let file = Arc::new(File::open(path)?);
+        inner.handles.insert(file_id, (Arc::clone(&file), now));
+
+        Ok(file)
+    }
+
+    /// Drops the cached handle for `file_id`, e.g. because the file was deleted or replaced.
+    pub fn evict(&self, file_id: u64) {
+        self.inner
+            .lock()
+            .expect("Reader cache lock should not be poisoned")
+            .handles
+            .remove(&file_id);
+    }
+}
+
+/// Reads exactly `buf.len()` bytes from `file` at `offset`, without touching the file cursor.
+#[cfg(unix)]
+pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+/// Reads exactly `buf.len()` bytes from `file` at `offset`.
+#[cfg(windows)]
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::{io::ErrorKind, os::windows::fs::FileExt};
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
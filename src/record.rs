@@ -2,24 +2,118 @@
 //!
 //! # Record Format
 //! Each record is serialized as:
-//! - Header: (record_type: 1 byte, timestamp: 8 bytes, key_size: 4 bytes, value_size: 4 bytes)
+//! - Header: (version: 1 byte, checksum: 4 bytes, record_type: 1 byte, timestamp: 8 bytes, expires_at: 8 bytes, seq: 8 bytes, key_size: 4 bytes, value_size: 4 bytes)
 //! - Payload: (key: n bytes, value: n bytes)
 //!
 //! Buffer layout:
+//! `version | checksum | record_type | timestamp | expires_at | seq | key_size | value_size | key | value`
+//!
+//! The checksum is a CRC32 over every other byte of the record (version, the rest of the header, key and value).
+//! `timestamp` and `expires_at` are in nanoseconds since the epoch, `expires_at` is `NO_EXPIRY` for records
+//! that never expire. `seq` numbers the writes of a database, see `KvStore::snapshot`; the records of a batch share one.
+//!
+//! # Older Versions
+//! Records of older versions are still readable, and are decoded as if they had the fields they lack:
+//! - Version 2 (`RECORD_V2`) has no `seq`, and keeps `timestamp` and `expires_at` in whole seconds.
+//! - Version 1 (`RECORD_V1`) has no `expires_at` either, so it never expires.
+//!
+//! Their `seq` decodes as `0`, and their timestamps are converted to nanoseconds.
+//!
+//! # Legacy Format
+//! Records written before checksums were introduced have no version or checksum:
 //! `record_type | timestamp | key_size | value_size | key | value`
+//!
+//! They are still readable. Since a legacy record always starts with its `RecordType` (`0` or `1`),
+//! the first byte of a record is enough to tell the two formats apart.
+//! Only logs from before the database had a manifest can hold them, and never after a versioned record,
+//! so recovery treats a `0` or `1` anywhere else as corruption rather than an unchecked record.
+//!
+//! # Batches
+//! The records of a `WriteBatch` are written between a `BatchBegin` and a `BatchCommit` marker.
+//! Markers have an empty key and value. A batch without its commit marker was cut short and is ignored.
+//!
+//! # Merges
+//! A `Merge` record holds an operand rather than a value. The value of a key is its newest `Put`
+//! with every later operand folded in by the `MergeOperator`, see `KvDB::merge`.
 
 use std::time::SystemTime;
 
+use crate::helper::{Crc32, system_time_to_bytes};
+
+/// Version byte of checksummed records without an expiry.
+pub const RECORD_V1: u8 = 0xA1;
+/// Version byte of records with an expiry in whole seconds and no sequence number.
+pub const RECORD_V2: u8 = 0xA2;
+/// Version byte that starts every record written now.
+pub const RECORD_V3: u8 = 0xA3;
+/// The `expires_at` of a record that never expires.
+pub const NO_EXPIRY: i64 = 0;
+
+/// Number of bytes used to store the format version.
+pub const VERSION_SIZE: usize = 1; // 1 byte version marker
+/// Number of bytes used to store the checksum.
+pub const CRC_SIZE: usize = 4; // 4 bytes CRC32
+/// Number of bytes used to store the record type.
+pub const TYPE_SIZE: usize = 1; // 1 byte for RecordType
+/// Number of bytes used to store the length of key/value.
+pub const LEN_SIZE: usize = 4; // 4 bytes for u32 lengths
+/// Number of bytes used to store the timestamp.
+pub const TIMESTAMP_SIZE: usize = 8; // 8 bytes timestamp
+/// Number of bytes used to store the expiry.
+pub const EXPIRY_SIZE: usize = 8; // 8 bytes expiry timestamp
+/// Number of bytes used to store the sequence number.
+pub const SEQ_SIZE: usize = 8; // 8 bytes u64 sequence number
+/// Size of the header of a legacy (unversioned) record in bytes.
+pub const LEGACY_HEADER_SIZE: usize = TYPE_SIZE + TIMESTAMP_SIZE + LEN_SIZE + LEN_SIZE;
+/// Size of the header of a version 1 record in bytes.
+pub const HEADER_V1_SIZE: usize = VERSION_SIZE + CRC_SIZE + LEGACY_HEADER_SIZE;
+/// Size of the header of a version 2 record in bytes.
+pub const HEADER_V2_SIZE: usize = HEADER_V1_SIZE + EXPIRY_SIZE;
+/// Total size of the record header in bytes, the largest of all versions.
+pub const HEADER_SIZE: usize = HEADER_V2_SIZE + SEQ_SIZE;
+/// Nanoseconds in a second, to convert the timestamps of older records.
+pub const NANOS_PER_SEC: i64 = 1_000_000_000;
+
 /// The type of operation represented by a record in the log.
 ///
 /// - `Put`: Insert or update a key-value pair.
 /// - `Delete`: Remove a key-value pair.
+/// - `BatchBegin` / `BatchCommit`: Mark the start and end of a write batch.
+/// - `Merge`: Combine an operand with the current value, see `MergeOperator`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RecordType {
     /// Insert or update a key-value pair.
     Put = 0,
     /// Remove a key-value pair.
     Delete = 1,
+    /// Starts a write batch.
+    BatchBegin = 2,
+    /// Ends a write batch, its records only count once this is on disk.
+    BatchCommit = 3,
+    /// An operand for the merge operator, applied to the value when the key is read.
+    Merge = 4,
+}
+
+impl RecordType {
+    /// Whether this is a batch marker rather than an operation on a key.
+    pub fn is_marker(self) -> bool {
+        matches!(self, RecordType::BatchBegin | RecordType::BatchCommit)
+    }
+}
+
+impl TryFrom<u8> for RecordType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(RecordType::Put),
+            1 => Ok(RecordType::Delete),
+            2 => Ok(RecordType::BatchBegin),
+            3 => Ok(RecordType::BatchCommit),
+            4 => Ok(RecordType::Merge),
+            other => Err(other),
+        }
+    }
 }
 
 /// Represents a single log record for a key-value operation.
@@ -27,17 +121,225 @@ pub enum RecordType {
 /// A record contains the operation type, a timestamp, and the key-value data.
 ///
 /// # Fields
-/// - `record_type`: The type of operation (Put or Delete).
+/// - `record_type`: The type of operation (Put, Delete or Merge).
 /// - `timestamp`: The time the operation was performed.
+/// - `expires_at`: When a Put stops being visible, if ever.
+/// - `seq`: The sequence number of the write.
 /// - `key`: The key affected by the operation.
-/// - `value`: The value to store (empty for Delete operations).
+/// - `value`: The value to store, or the operand of a Merge (empty for Delete operations).
 pub struct Record<'a> {
     /// The type of operation (Put or Delete).
     pub record_type: RecordType,
     /// The time the operation was performed.
     pub timestamp: SystemTime,
+    /// When the value stops being visible, `None` if it never expires.
+    pub expires_at: Option<SystemTime>,
+    /// The sequence number of the write.
+    pub seq: u64,
     /// The key affected by the operation.
     pub key: &'a [u8],
     /// The value to store (empty for Delete operations).
     pub value: &'a [u8],
 }
+
+impl Record<'_> {
+    /// Serializes the record header, including the checksum over the header and payload.
+    pub fn encode_header(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        let mut pos = VERSION_SIZE + CRC_SIZE;
+
+        header[0] = RECORD_V3;
+        header[pos] = self.record_type as u8;
+        pos += TYPE_SIZE;
+        header[pos..pos + TIMESTAMP_SIZE].copy_from_slice(&system_time_to_bytes(&self.timestamp));
+        pos += TIMESTAMP_SIZE;
+        let expires_at = self
+            .expires_at
+            .as_ref()
+            .map_or(NO_EXPIRY.to_le_bytes(), system_time_to_bytes);
+        header[pos..pos + EXPIRY_SIZE].copy_from_slice(&expires_at);
+        pos += EXPIRY_SIZE;
+        header[pos..pos + SEQ_SIZE].copy_from_slice(&self.seq.to_le_bytes());
+        pos += SEQ_SIZE;
+        header[pos..pos + LEN_SIZE].copy_from_slice(&(self.key.len() as u32).to_le_bytes());
+        pos += LEN_SIZE;
+        header[pos..pos + LEN_SIZE].copy_from_slice(&(self.value.len() as u32).to_le_bytes());
+
+        let checksum = checksum(&header, self.key, self.value);
+        header[VERSION_SIZE..VERSION_SIZE + CRC_SIZE].copy_from_slice(&checksum.to_le_bytes());
+
+        header
+    }
+}
+
+/// Computes the checksum of a record from its encoded header and payload.
+///
+/// The checksum field itself is skipped, so `header` may already contain it.
+fn checksum(header: &[u8], key: &[u8], value: &[u8]) -> u32 {
+    Crc32::new()
+        .update(&header[..VERSION_SIZE])
+        .update(&header[VERSION_SIZE + CRC_SIZE..])
+        .update(key)
+        .update(value)
+        .finish()
+}
+
+/// Whether a value with the given `expires_at` is expired at `now`, both in nanoseconds since the epoch.
+pub fn is_expired(expires_at: i64, now: i64) -> bool {
+    expires_at != NO_EXPIRY && expires_at <= now
+}
+
+/// Whether `byte` is the record type of a legacy record.
+pub fn is_legacy_type(byte: u8) -> bool {
+    matches!(
+        RecordType::try_from(byte),
+        Ok(RecordType::Put | RecordType::Delete)
+    )
+}
+
+/// A decoded record header.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    /// The format version, `0` for legacy records.
+    pub version: u8,
+    /// The stored checksum (always `0` for legacy records).
+    pub checksum: u32,
+    /// The type of operation.
+    pub record_type: RecordType,
+    /// Nanoseconds since UNIX_EPOCH at which the operation was performed.
+    pub timestamp: i64,
+    /// Nanoseconds since UNIX_EPOCH at which the value expires, `NO_EXPIRY` if it never does.
+    pub expires_at: i64,
+    /// The sequence number of the write, `0` for records from before sequence numbers.
+    pub seq: u64,
+    /// Length of the key in bytes.
+    pub key_len: u32,
+    /// Length of the value in bytes.
+    pub value_len: u32,
+}
+
+impl Header {
+    /// Returns the size of the header for a record starting with `first_byte`,
+    /// or `None` if the byte does not start a known record format.
+    pub fn size_for(first_byte: u8) -> Option<usize> {
+        match first_byte {
+            RECORD_V1 => Some(HEADER_V1_SIZE),
+            RECORD_V2 => Some(HEADER_V2_SIZE),
+            RECORD_V3 => Some(HEADER_SIZE),
+            // batches came after checksums, so legacy records are only ever puts and deletes
+            byte if is_legacy_type(byte) => Some(LEGACY_HEADER_SIZE),
+            _ => None,
+        }
+    }
+
+    /// Decodes a header from `buf`, which must hold exactly `Header::size_for(buf[0])` bytes.
+    ///
+    /// Returns `None` if the header is not valid.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let (version, checksum, fields) = match *buf.first()? {
+            version @ (RECORD_V1 | RECORD_V2 | RECORD_V3)
+                if Some(buf.len()) == Self::size_for(version) =>
+            {
+                let checksum = u32::from_le_bytes(
+                    buf[VERSION_SIZE..VERSION_SIZE + CRC_SIZE]
+                        .try_into()
+                        .expect("Checksum size should be 4bytes"),
+                );
+                (version, checksum, &buf[VERSION_SIZE + CRC_SIZE..])
+            }
+            byte if is_legacy_type(byte) && buf.len() == LEGACY_HEADER_SIZE => (0, 0, buf),
+            _ => return None,
+        };
+
+        let record_type = RecordType::try_from(fields[0]).ok()?;
+        let mut pos = TYPE_SIZE;
+
+        let timestamp = i64::from_le_bytes(
+            fields[pos..pos + TIMESTAMP_SIZE]
+                .try_into()
+                .expect("timestamp size should be 8bytes"),
+        );
+        pos += TIMESTAMP_SIZE;
+
+        let expires_at = match version {
+            RECORD_V2 | RECORD_V3 => {
+                let expires_at = i64::from_le_bytes(
+                    fields[pos..pos + EXPIRY_SIZE]
+                        .try_into()
+                        .expect("Expiry size should be 8bytes"),
+                );
+                pos += EXPIRY_SIZE;
+                expires_at
+            }
+            _ => NO_EXPIRY,
+        };
+
+        let seq = match version {
+            RECORD_V3 => {
+                let seq = u64::from_le_bytes(
+                    fields[pos..pos + SEQ_SIZE]
+                        .try_into()
+                        .expect("Sequence number size should be 8bytes"),
+                );
+                pos += SEQ_SIZE;
+                seq
+            }
+            _ => 0,
+        };
+
+        // older versions count in whole seconds
+        let (timestamp, expires_at) = match version {
+            RECORD_V3 => (timestamp, expires_at),
+            _ => (
+                timestamp.saturating_mul(NANOS_PER_SEC),
+                expires_at.saturating_mul(NANOS_PER_SEC),
+            ),
+        };
+
+        let key_len = u32::from_le_bytes(
+            fields[pos..pos + LEN_SIZE]
+                .try_into()
+                .expect("Key size should be 4bytes"),
+        );
+        pos += LEN_SIZE;
+
+        let value_len = u32::from_le_bytes(
+            fields[pos..pos + LEN_SIZE]
+                .try_into()
+                .expect("Value size should be 4bytes"),
+        );
+
+        Some(Header {
+            version,
+            checksum,
+            record_type,
+            timestamp,
+            expires_at,
+            seq,
+            key_len,
+            value_len,
+        })
+    }
+
+    /// Size of the encoded header in bytes.
+    pub fn size(&self) -> usize {
+        match self.version {
+            RECORD_V1 => HEADER_V1_SIZE,
+            RECORD_V2 => HEADER_V2_SIZE,
+            RECORD_V3 => HEADER_SIZE,
+            _ => LEGACY_HEADER_SIZE,
+        }
+    }
+
+    /// Total size of the record on disk (header, key and value).
+    pub fn record_size(&self) -> usize {
+        self.size() + self.key_len as usize + self.value_len as usize
+    }
+
+    /// Checks the stored checksum against the record's encoded header and payload.
+    ///
+    /// Legacy records carry no checksum and always pass.
+    pub fn verify(&self, header: &[u8], key: &[u8], value: &[u8]) -> bool {
+        self.version == 0 || checksum(header, key, value) == self.checksum
+    }
+}
@@ -0,0 +1,176 @@
+//! Point-in-time views of the database, see `KvDB::snapshot`.
+//!
+//! Every write is numbered, and a snapshot remembers the number of the last write it sees,
+//! along with the time it was taken, which values with a TTL expire against.
+//! While it is alive the store keeps each index entry a later write replaces, so the snapshot can
+//! still find the records it saw, and compaction leaves the logs holding them on disk.
+//! Snapshots live in memory only and do not survive a restart.
+//! A scan of a snapshot keeps it registered too, so it stays valid after the `Snapshot` itself is dropped.
+
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    error::KvError,
+    iter::{self, Iter},
+    store::{KvStore, SnapshotPoint},
+};
+
+/// A read-only view of the database as it was when the snapshot was taken.
+///
+/// Writes made afterwards, by this handle or any other, are not visible through it,
+/// and values with a TTL expire through it as of the time it was taken.
+/// Holding on to a snapshot keeps replaced records on disk, so drop it once the reads are done.
+///
+/// ```no_run
+/// use kv_db::{db::KvDB, store::DbTraits};
+///
+/// let mut db = KvDB::open("./data").unwrap();
+/// db.put(b"a", b"1").unwrap();
+///
+/// let snapshot = db.snapshot();
+/// db.put(b"a", b"2").unwrap();
+///
+/// assert_eq!(snapshot.get(b"a").unwrap(), Some(b"1".to_vec()));
+/// ```
+pub struct Snapshot {
+    store: Arc<Mutex<KvStore>>,
+    guard: Arc<SnapshotGuard>,
+}
+
+/// Keeps a snapshot registered with the store until the snapshot and every scan of it are dropped.
+pub(crate) struct SnapshotGuard {
+    store: Arc<Mutex<KvStore>>,
+    pub(crate) point: SnapshotPoint,
+}
+
+impl Snapshot {
+    pub(crate) fn new(store: Arc<Mutex<KvStore>>) -> Self {
+        let point = store
+            .lock()
+            .expect("Store lock should not be poisoned")
+            .snapshot();
+
+        let guard = Arc::new(SnapshotGuard {
+            store: Arc::clone(&store),
+            point,
+        });
+
+        Self { store, guard }
+    }
+
+    /// The sequence number of the last write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.guard.point.seq
+    }
+
+    /// Retrieves the value `key` had when the snapshot was taken.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        self.lock().get_at(key, Some(self.guard.point))
+    }
+
+    /// Scans every key and value of the snapshot in key order, see `KvDB::iter`.
+    pub fn iter(&self) -> Iter {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Scans the keys in `range` and their values as of the snapshot, see `KvDB::range`.
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Iter {
+        let (start, end) = iter::bounds(range);
+
+        Iter::new(
+            Arc::clone(&self.store),
+            start,
+            end,
+            Some(Arc::clone(&self.guard)),
+        )
+    }
+
+    /// Scans the keys starting with `prefix` and their values as of the snapshot.
+    pub fn prefix(&self, prefix: &[u8]) -> Iter {
+        Iter::new(
+            Arc::clone(&self.store),
+            Bound::Included(prefix.to_vec()),
+            iter::prefix_end(prefix),
+            Some(Arc::clone(&self.guard)),
+        )
+    }
+
+    fn lock(&self) -> MutexGuard<'_, KvStore> {
+        self.store
+            .lock()
+            .expect("Store lock should not be poisoned")
+    }
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        self.store
+            .lock()
+            .expect("Store lock should not be poisoned")
+            .release_snapshot(self.point.seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::{
+        db::KvDB,
+        options::Options,
+        store::{DbTraits, tests::temp_dir},
+    };
+
+    #[test]
+    fn scan_outlives_snapshot() {
+        let mut db = KvDB::open(temp_dir("scan_outlives_snapshot")).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let snapshot = db.snapshot();
+        let iter = snapshot.iter();
+        drop(snapshot);
+
+        db.put(b"a", b"2").unwrap();
+        db.put(b"b", b"1").unwrap();
+
+        let pairs: Vec<_> = iter.map(Result::unwrap).collect();
+        assert_eq!(pairs, vec![(b"a".to_vec(), b"1".to_vec())]);
+    }
+
+    #[test]
+    fn values_expire_as_of_the_snapshot() {
+        let mut db = KvDB::open(temp_dir("snapshot_expiry")).unwrap();
+        db.put_with_ttl(b"a", b"1", Duration::from_millis(50))
+            .unwrap();
+
+        let snapshot = db.snapshot();
+        thread::sleep(Duration::from_millis(80));
+
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(snapshot.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.iter().keys().collect::<Vec<_>>(), [b"a"]);
+        assert_eq!(db.snapshot().get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn compacting_expired_values_keeps_them_in_older_snapshots() {
+        let options = Options::builder().max_file_size(32).build();
+        let mut db = KvDB::open_with(temp_dir("snapshot_expiry_compacted"), options).unwrap();
+        db.put_with_ttl(b"a", b"1", Duration::from_millis(50))
+            .unwrap();
+        db.put(b"b", b"1").unwrap();
+
+        let snapshot = db.snapshot();
+        thread::sleep(Duration::from_millis(80));
+
+        assert!(db.compact().unwrap().files_read > 0);
+        db.put(b"c", b"1").unwrap();
+
+        assert_eq!(snapshot.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"c").unwrap(), None);
+        assert_eq!(db.snapshot().get(b"a").unwrap(), None);
+    }
+}
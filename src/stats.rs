@@ -0,0 +1,71 @@
+//! Space usage of the log files, see `KvDB::stats`.
+
+use crate::record::{NO_EXPIRY, is_expired};
+
+/// Live and dead bytes of a single log file.
+///
+/// A record is live while the index points at it. It becomes dead once its key is written again or deleted.
+/// Tombstones are always dead, they only exist to shadow older records.
+/// Values with a TTL count as dead once the last of them in the file expired.
+/// A record a merge builds on counts as dead too, as compaction folds it into the merged value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileStats {
+    pub file_id: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub(crate) expiring_bytes: u64, // the part of the live bytes that has a TTL
+    pub(crate) latest_expiry: i64,  // when the last of those expires, `NO_EXPIRY` if none
+}
+
+impl FileStats {
+    /// Size of the file on disk.
+    pub fn total_bytes(&self) -> u64 {
+        self.live_bytes + self.dead_bytes
+    }
+
+    /// The share of the file that compaction would reclaim, between 0 and 1.
+    pub fn garbage_ratio(&self) -> f64 {
+        match self.total_bytes() {
+            0 => 0.0,
+            total => self.dead_bytes as f64 / total as f64,
+        }
+    }
+
+    /// Adds a live value that expires at `expires_at`, if it ever does.
+    pub(crate) fn add_expiring(&mut self, size: u64, expires_at: i64) {
+        if expires_at != NO_EXPIRY {
+            self.expiring_bytes += size;
+            self.latest_expiry = self.latest_expiry.max(expires_at);
+        }
+    }
+
+    /// The stats as of `now`, with the values that have a TTL moved to the dead bytes once all of them expired.
+    pub(crate) fn at(mut self, now: i64) -> Self {
+        if is_expired(self.latest_expiry, now) {
+            self.live_bytes = self.live_bytes.saturating_sub(self.expiring_bytes);
+            self.dead_bytes += self.expiring_bytes;
+            self.expiring_bytes = 0;
+        }
+
+        self
+    }
+}
+
+/// A snapshot of the store's space usage.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Number of live keys. Expired keys count until compaction reclaims them.
+    pub keys: usize,
+    /// Every live log file in replay order, the active one last.
+    pub files: Vec<FileStats>,
+}
+
+impl Stats {
+    pub fn live_bytes(&self) -> u64 {
+        self.files.iter().map(|file| file.live_bytes).sum()
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        self.files.iter().map(|file| file.dead_bytes).sum()
+    }
+}
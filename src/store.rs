@@ -1,34 +1,33 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     fs::{self, File},
-    io::{IoSlice, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    ops::Bound,
+    path::{Path, PathBuf},
     sync::{
         Arc,
-        mpsc::{self, Receiver, Sender},
+        mpsc::{self, Receiver, SyncSender},
     },
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
+    batch::WriteBatch,
+    compaction::{CompactionOutput, CompactionPlan},
+    entry::{Entry, Metadata},
     error::KvError,
-    helper::system_time_to_bytes,
-    record::{Record, RecordType},
-    wal::should_rotate,
+    helper::system_time_to_nanos,
+    hint::{HintEntry, read_hint, write_hint},
+    index::{IndexEntry, Indexer, is_empty_range, new_indexer},
+    manifest::{MANIFEST_NAME, Manifest},
+    merge::MergeOperator,
+    options::{Options, RecoveryMode, SyncPolicy},
+    reader::{ReaderCache, read_exact_at},
+    record::{HEADER_SIZE, Header, NO_EXPIRY, Record, RecordType, is_expired, is_legacy_type},
+    stats::{FileStats, Stats},
+    wal::LogWriter,
 };
 
-/// The maximum size (in bytes) of uncompacted data before triggering compaction (500MB).
-const MAX_COMPACTION_SIZE: u64 = 1024 * 1024 * 10; // 500MB
-
-/// Number of bytes used to store the record type.
-const TYPE_SIZE: usize = 1; // 1 byte for RecordType
-/// Number of bytes used to store the length of key/value.
-const LEN_SIZE: usize = 4; // 4 bytes for u32 lengths
-/// Number of bytes used to store the timestamp.
-const TIMESTAMP_SIZE: usize = 8; // 8 bytes timestamp
-/// Total size of the record header in bytes.
-const HEADER_SIZE: usize = TYPE_SIZE + TIMESTAMP_SIZE + LEN_SIZE + LEN_SIZE;
-
 pub trait DbTraits: Sized {
     fn open(path: impl Into<PathBuf>) -> Result<Self, KvError>;
     fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), KvError>;
@@ -36,275 +35,1300 @@ pub trait DbTraits: Sized {
     fn delete(&mut self, key: &[u8]) -> Result<(), KvError>;
 }
 
-/// Appends a record to the specified log file.
+/// The outcome of a conditional write, see `KvDB::compare_and_swap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CasResult {
+    /// The condition held and the write was applied.
+    Applied,
+    /// The condition did not hold and nothing was written. Holds the current value, `None` if the key is missing.
+    Failed(Option<Vec<u8>>),
+}
+
+/// The state of the store a snapshot reads, see `KvStore::snapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotPoint {
+    /// The sequence number of the last write the snapshot sees.
+    pub seq: u64,
+    /// When the snapshot was taken, in nanoseconds since the epoch. Values expire as of this time.
+    pub taken_at: i64,
+}
+
+impl CasResult {
+    /// Whether the condition held and the write was applied.
+    pub fn is_applied(&self) -> bool {
+        matches!(self, CasResult::Applied)
+    }
+}
+
+/// Reads and decodes the record header at the reader's current position, which is `offset` in `file_path`.
 ///
-/// # Arguments
-/// * `record` - The record to append (Put/Delete).
-/// * `file_path` - The path to the log file.
+/// # Returns
+/// Returns the decoded header together with its raw bytes (only the first `header.size()` bytes are used),
+/// which are needed to verify the checksum.
+///
+/// # Errors
+/// Returns `KvError::Corruption` if the header is invalid, and an IO error if it is cut short.
+fn read_header(
+    reader: &mut impl Read,
+    file_path: &Path,
+    offset: u64,
+) -> Result<(Header, [u8; HEADER_SIZE]), KvError> {
+    let corruption = || KvError::Corruption {
+        file: file_path.to_path_buf(),
+        offset,
+    };
+
+    let mut buf = [0u8; HEADER_SIZE];
+
+    reader.read_exact(&mut buf[..1])?;
+
+    let header_size = Header::size_for(buf[0]).ok_or_else(corruption)?;
+
+    reader.read_exact(&mut buf[1..header_size])?;
+
+    let header = Header::decode(&buf[..header_size]).ok_or_else(corruption)?;
+
+    Ok((header, buf))
+}
+
+/// Reads the key and value that follow `header` and verifies the record's checksum.
+///
+/// # Errors
+/// Returns `KvError::Corruption` if the checksum does not match, and an IO error if the payload is cut short.
+fn read_payload(
+    reader: &mut impl Read,
+    header: &Header,
+    raw_header: &[u8],
+    file_path: &Path,
+    offset: u64,
+) -> Result<(Vec<u8>, Vec<u8>), KvError> {
+    let mut key = vec![0u8; header.key_len as usize];
+    reader.read_exact(&mut key)?;
+
+    let mut value = vec![0u8; header.value_len as usize];
+    reader.read_exact(&mut value)?;
+
+    if !header.verify(&raw_header[..header.size()], &key, &value) {
+        return Err(KvError::Corruption {
+            file: file_path.to_path_buf(),
+            offset,
+        });
+    }
+
+    Ok((key, value))
+}
+
+/// Reads the record at the reader's current position, which is `offset` in `file_path`, and verifies its checksum.
+///
+/// `size` is the expected size of the record on disk, so a corrupted length can not trigger a huge allocation.
 ///
 /// # Returns
-/// Returns the number of bytes written and the offset at which the record was written.
-fn append(record: Record, file_path: impl Into<PathBuf>) -> Result<(usize, u64), KvError> {
-    let key = record.key;
-    let value = record.value;
-    let record_type = &[record.record_type as u8];
-    let timestamp = system_time_to_bytes(&record.timestamp);
-    let key_len = key.len() as u32;
-    let value_len = value.len() as u32;
-
-    let mut file = File::options()
-        .create(true)
-        .append(true)
-        .open(file_path.into())?;
-
-    // current size of the log file before appending
-    let offset = file.metadata()?.len();
-
-    let key_len_bytes = key_len.to_le_bytes();
-    let value_len_bytes = value_len.to_le_bytes();
-
-    // buffer contents: record_type 1byte | timestamp 8bytes | key_size 4bytes | value_size 4bytes | key n-bytes | value n-bytes
-    let bufs = [
-        IoSlice::new(record_type),
-        IoSlice::new(&timestamp),
-        IoSlice::new(&key_len_bytes),
-        IoSlice::new(&value_len_bytes), // 0 for Delete
-        IoSlice::new(key),
-        IoSlice::new(value), // Would be empty for Delete
-    ];
-
-    let size = file.write_vectored(&bufs)?;
-
-    file.sync_all()?;
-
-    Ok((size, offset))
+/// Returns the decoded header together with the key and value.
+///
+/// # Errors
+/// Returns `KvError::Corruption` if the record is invalid, has an unexpected size or fails its checksum.
+fn read_entry(
+    reader: &mut impl Read,
+    file_path: &Path,
+    offset: u64,
+    size: usize,
+) -> Result<(Header, Vec<u8>, Vec<u8>), KvError> {
+    let corruption = || KvError::Corruption {
+        file: file_path.to_path_buf(),
+        offset,
+    };
+
+    let (header, raw_header) = read_header(reader, file_path, offset).map_err(|err| match err {
+        KvError::Io(err) if err.kind() == ErrorKind::UnexpectedEof => corruption(),
+        other => other,
+    })?;
+
+    if header.record_size() != size {
+        return Err(corruption());
+    }
+
+    let (key, value) = read_payload(reader, &header, &raw_header, file_path, offset)?;
+
+    Ok((header, key, value))
 }
 
-/// Reads a value and timestamp from the log file at the given offset.
+/// Reads a value (or merge operand) and its record header from the record an index entry points at.
+///
+/// The whole record is fetched with a single positional read through a cached file handle.
 ///
 /// # Arguments
-/// * `offset` - The offset in the file to start reading from.
-/// * `file_path` - The path to the log file.
+/// * `readers` - The cache of open log file handles.
+/// * `dir_path` - The database directory, which holds the log file.
+/// * `entry` - The location and size of the record.
 ///
 /// # Returns
-/// Returns `Some((value, timestamp))` if a valid Put record is found, otherwise `None`.
+/// Returns `Some((value, header))` if a valid Put or Merge record is found, otherwise `None`.
+/// The header holds the record's timestamp, expiry and sequence number.
 ///
 /// # Errors
-/// Returns an error if the file cannot be read or the header is invalid.
+/// Returns an error if the file cannot be read, or `KvError::Corruption` if the record fails its checksum.
+pub(crate) fn read(
+    readers: &ReaderCache,
+    dir_path: &Path,
+    entry: &IndexEntry,
+) -> Result<Option<(Vec<u8>, Header)>, KvError> {
+    let file_path = &log_path(dir_path, entry.file_id());
+    let file = readers.get(entry.file_id(), file_path)?;
+    let (offset, size) = (entry.offset, entry.size as usize);
+
+    let mut buf = vec![0u8; size];
+
+    read_exact_at(&file, &mut buf, offset).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => KvError::Corruption {
+            file: file_path.to_path_buf(),
+            offset,
+        },
+        _ => KvError::Io(err),
+    })?;
+
+    let (header, _, value) = read_entry(&mut buf.as_slice(), file_path, offset, size)?;
+
+    if !matches!(header.record_type, RecordType::Put | RecordType::Merge) {
+        return Ok(None);
+    }
+
+    Ok(Some((value, header)))
+}
+
+/// Folds the records of a key, oldest first, into its value: the last put with every later merge operand applied.
+///
+/// A put that operands build on counts as missing once it expired. The newest record is never checked for expiry,
+/// that is up to the caller.
 ///
-/// TODO: Update the return type for more flexibility.
-fn read(offset: u64, file_path: impl Into<PathBuf>) -> Result<Option<(Vec<u8>, i64)>, KvError> {
-    let mut file = File::open(file_path.into())?;
+/// # Returns
+/// Returns the value with the header of the newest record, or `None` if there is no value.
+///
+/// # Errors
+/// Returns `KvError::NoMergeOperator` if there are operands to apply but no merge operator.
+pub(crate) fn resolve(
+    readers: &ReaderCache,
+    dir_path: &Path,
+    key: &[u8],
+    records: &[IndexEntry],
+    merge_operator: Option<&dyn MergeOperator>,
+) -> Result<Option<(Vec<u8>, Header)>, KvError> {
+    let now = now_nanos();
+    let mut resolved: Option<(Option<Vec<u8>>, Header)> = None;
+
+    for (i, entry) in records.iter().enumerate() {
+        let (value, header) = match read(readers, dir_path, entry)? {
+            Some(val) => val,
+            None => continue,
+        };
 
-    file.seek(SeekFrom::Start(offset))?;
+        let value = match header.record_type {
+            RecordType::Merge => {
+                let merge_operator = merge_operator.ok_or(KvError::NoMergeOperator)?;
+                let existing = resolved.and_then(|(existing, _)| existing);
 
-    let mut header = [0u8; HEADER_SIZE];
+                Some(merge_operator.merge(key, existing.as_deref(), &value))
+            }
+            _ if i + 1 < records.len() && is_expired(header.expires_at, now) => None,
+            _ => Some(value),
+        };
 
-    file.read_exact(&mut header)?;
+        resolved = Some((value, header));
+    }
 
-    if header[0] != RecordType::Put as u8 {
-        return Ok(None);
+    Ok(resolved.and_then(|(value, header)| Some((value?, header))))
+}
+
+/// The current time in nanoseconds since the epoch, to check expiry against.
+fn now_nanos() -> i64 {
+    system_time_to_nanos(&SystemTime::now())
+}
+
+/// The path of the log file with the given id.
+pub(crate) fn log_path(dir_path: &Path, file_id: u64) -> PathBuf {
+    dir_path.join(format!("{}.log", file_id))
+}
+
+/// Parses the numeric file id from a log path such as `3.log`.
+fn log_file_id(path: &Path) -> Option<u64> {
+    if path.extension()? != "log" {
+        return None;
+    }
+
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Lists the log files in `dir_path`, sorted by ascending file id.
+///
+/// Files that are not named `<id>.log` (e.g. a `compacted.log` left by older versions) are skipped.
+fn list_logs(dir_path: &Path) -> Result<Vec<(u64, PathBuf)>, KvError> {
+    if !dir_path.is_dir() {
+        return Err(KvError::InvalidDir);
     }
 
-    let timestamp = i64::from_le_bytes(
-        header[TYPE_SIZE..TYPE_SIZE + TIMESTAMP_SIZE]
-            .try_into()
-            .expect("timestamp size should be 4bytes"),
-    );
+    let mut logs = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let path = entry?.path();
 
-    let key_len = u32::from_le_bytes(
-        header[TYPE_SIZE + TIMESTAMP_SIZE..TYPE_SIZE + TIMESTAMP_SIZE + LEN_SIZE]
-            .try_into()
-            .expect("Key size should be 4bytes"),
-    ) as usize;
+        match log_file_id(&path) {
+            Some(file_id) if path.is_file() => logs.push((file_id, path)),
+            // hint files are looked up next to their logs
+            None if path.extension().is_some_and(|ext| ext == "hint") => {}
+            None if path.file_stem().is_some_and(|stem| stem == MANIFEST_NAME) => {}
+            _ => println!("[Info]: Skipping unknown file {}", path.display()),
+        }
+    }
 
-    let value_len = u32::from_le_bytes(
-        header[TYPE_SIZE + TIMESTAMP_SIZE + LEN_SIZE
-            ..TYPE_SIZE + TIMESTAMP_SIZE + LEN_SIZE + LEN_SIZE]
-            .try_into()
-            .expect("Value size should be 4bytes"),
-    ) as usize;
+    logs.sort_unstable_by_key(|(file_id, _)| *file_id);
 
-    // skip the key
-    file.seek(SeekFrom::Current(key_len as i64))?;
+    Ok(logs)
+}
 
-    let mut value = vec![0u8; value_len];
+/// Deletes a log file along with its hint.
+///
+/// The hint goes first, so a crash in between never leaves a hint without its log.
+fn remove_log(log_path: &Path) -> Result<(), KvError> {
+    if let Err(err) = fs::remove_file(log_path.with_extension("hint"))
+        && err.kind() != ErrorKind::NotFound
+    {
+        return Err(err.into());
+    }
 
-    file.read_exact(&mut value)?;
+    fs::remove_file(log_path)?;
 
-    Ok(Some((value.to_vec(), timestamp)))
+    Ok(())
+}
+
+/// An index entry a write replaced, as `(seq of the write, entry, operands the entry builds on)`.
+type Replaced = (u64, Option<IndexEntry>, Vec<IndexEntry>);
+
+/// Deletes logs that are no longer live, a file that can't be deleted now is removed on the next startup.
+pub(crate) fn delete_logs(log_paths: &[PathBuf]) {
+    for log_path in log_paths {
+        if let Err(err) = remove_log(log_path) {
+            println!(
+                "[Warn]: Failed to delete compacted log {}: {:?}",
+                log_path.display(),
+                err
+            );
+        }
+    }
 }
 
 /// The main key-value store structure, holding the in-memory index and managing log files.
 #[derive(Debug)]
 pub struct KvStore {
-    memory_store: HashMap<Vec<u8>, (PathBuf, u64, usize)>, //file_id, offset, size
+    memory_store: Box<dyn Indexer>,
     dir_path: PathBuf,
-    compaction_size: usize,
+    file_stats: HashMap<u64, FileStats>, // live and dead bytes of every log file
     current_file_id: u64,
-    pub sender: Arc<Sender<()>>,
-    rx: Receiver<()>,
+    next_file_id: u64, // ids are never reused, so a compaction output can't clobber a live file
+    manifest: Manifest,
+    active: Option<LogWriter>, // `None` when opened read-only
+    readers: ReaderCache,
+    pub sender: Arc<SyncSender<()>>,
+    rx: Option<Receiver<()>>, // taken by the compaction worker
     pub running: bool,
+    options: Options,
+    written: u64, // records appended since open
+    synced: u64,  // records flushed to disk since open
+    last_sync: Instant,
+    seq: u64, // sequence number of the last write, see `KvStore::snapshot`
+    snapshots: BTreeMap<u64, usize>, // live snapshots by sequence number, and how many share it
+    operands: HashMap<Vec<u8>, Vec<IndexEntry>>, // older records a merge builds on, oldest first
+    history: BTreeMap<Vec<u8>, Vec<Replaced>>, // replaced entries live snapshots may still read
+    obsolete: Vec<u64>, // compacted logs kept around for live snapshots
+    active_entries: Vec<HintEntry>, // every record in the active log, for its hint once it fills up
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            println!("[Warn]: Failed to sync the active log on close: {:?}", err);
+        }
+    }
 }
 
 impl KvStore {
+    /// Whether the logs worth compacting hold enough dead bytes to do so, see `KvStore::is_compactable`.
     pub fn check_compaction(&self) -> bool {
-        self.compaction_size > MAX_COMPACTION_SIZE as usize
+        let now = now_nanos();
+        let dead_bytes: u64 = self
+            .file_stats
+            .values()
+            .map(|stats| stats.at(now))
+            .filter(|stats| self.is_compactable(stats))
+            .map(|stats| stats.dead_bytes)
+            .sum();
+
+        dead_bytes > self.options.compaction_threshold
+    }
+
+    /// Whether a log is immutable and has a garbage ratio above `compaction_garbage_ratio`.
+    ///
+    /// `stats` must be as of now, see `FileStats::at`.
+    fn is_compactable(&self, stats: &FileStats) -> bool {
+        stats.file_id != self.current_file_id
+            && stats.garbage_ratio() > self.options.compaction_garbage_ratio
+    }
+
+    /// Approximate size (in bytes) of the in-memory index, including the keys it owns.
+    ///
+    /// Walks every key, so it takes time proportional to the number of keys.
+    pub fn memory_usage(&self) -> usize {
+        self.memory_store.memory_usage()
+    }
+
+    /// Whether the index keeps its keys in order, so `KvStore::scan_keys` can take a few at a time cheaply.
+    pub fn has_ordered_index(&self) -> bool {
+        self.memory_store.is_ordered()
     }
 
-    /// Reconstructs the in-memory index by scanning all log files in the directory.
+    /// The next keys of a scan between `start` and `end`, in key order, or from the back if `reverse` is set.
     ///
-    /// Updates the current file ID and compaction size as needed.
+    /// Returns at most `batch` keys. The hash index sorts the whole range on every call, so a scan of it
+    /// should take every key at once and work through them itself.
+    ///
+    /// A scan of a `snapshot` also gets the keys changed since it was taken, some of which may not
+    /// exist in it, see `KvStore::entry_at`.
+    pub fn scan_keys(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+        batch: usize,
+        snapshot: Option<SnapshotPoint>,
+    ) -> Vec<Vec<u8>> {
+        let range = self.memory_store.range(start, end);
+        let keys = range.map(|(key, _)| key);
+
+        let mut keys: Vec<Vec<u8>> = match reverse {
+            true => keys.rev().take(batch).collect(),
+            false => keys.take(batch).collect(),
+        };
+
+        if snapshot.is_none() || self.history.is_empty() || is_empty_range(start, end) {
+            return keys;
+        }
+
+        // the first `batch` keys of each side hold the first `batch` keys of both
+        let changed = self
+            .history
+            .range::<[u8], _>((start, end))
+            .map(|(key, _)| key.clone());
+
+        match reverse {
+            true => keys.extend(changed.rev().take(batch)),
+            false => keys.extend(changed.take(batch)),
+        }
+
+        keys.sort_unstable();
+        keys.dedup();
+
+        if reverse {
+            keys.reverse();
+        }
+
+        keys.truncate(batch);
+
+        keys
+    }
+
+    /// Takes a snapshot of the store as it is now.
+    ///
+    /// Until it is released with `KvStore::release_snapshot`, every entry a write replaces is kept
+    /// so the snapshot can still read it, and compacted logs are not deleted.
+    pub fn snapshot(&mut self) -> SnapshotPoint {
+        *self.snapshots.entry(self.seq).or_default() += 1;
+
+        SnapshotPoint {
+            seq: self.seq,
+            taken_at: now_nanos(),
+        }
+    }
+
+    /// Releases a snapshot taken with `KvStore::snapshot`, dropping whatever no other snapshot still needs.
+    pub fn release_snapshot(&mut self, seq: u64) {
+        if let Some(count) = self.snapshots.get_mut(&seq) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.snapshots.remove(&seq);
+            }
+        }
+
+        match self.snapshots.keys().next() {
+            Some(&oldest) => {
+                // an entry replaced at or before the oldest snapshot is never read again
+                self.history.retain(|_, versions| {
+                    versions.retain(|(changed, ..)| *changed > oldest);
+                    !versions.is_empty()
+                });
+            }
+            None => {
+                self.history.clear();
+
+                let obsolete = std::mem::take(&mut self.obsolete);
+                self.remove_logs(&obsolete);
+            }
+        }
+    }
+
+    /// The index entry of `key`, as of `snapshot` if one is given.
+    ///
+    /// Returns `None` if the key does not exist (or did not yet exist in the snapshot), or if its value expired
+    /// (by the time the snapshot was taken).
+    pub fn entry_at(&self, key: &[u8], snapshot: Option<SnapshotPoint>) -> Option<IndexEntry> {
+        self.version_at(key, snapshot).map(|(entry, _)| entry)
+    }
+
+    /// The index entry of `key` like `KvStore::entry_at`, along with the operands it builds on if it is a merge.
+    fn version_at(
+        &self,
+        key: &[u8],
+        snapshot: Option<SnapshotPoint>,
+    ) -> Option<(IndexEntry, &[IndexEntry])> {
+        // the state as of a snapshot is whatever the first write after it replaced
+        let (entry, operands) =
+            match snapshot
+                .zip(self.history.get(key))
+                .and_then(|(snapshot, versions)| {
+                    versions
+                        .iter()
+                        .find(|(changed, ..)| *changed > snapshot.seq)
+                }) {
+                Some((_, entry, operands)) => ((*entry)?, operands.as_slice()),
+                None => (
+                    self.memory_store.get(key)?,
+                    self.operands.get(key).map_or(&[][..], Vec::as_slice),
+                ),
+            };
+
+        // expired values stay in the index until compaction reclaims them
+        let now = snapshot.map_or_else(now_nanos, |snapshot| snapshot.taken_at);
+
+        (!entry.is_expired(now)).then_some((entry, operands))
+    }
+
+    /// Retrieves the value of `key` like `DbTraits::get`, as of `snapshot` if one is given.
+    pub fn get_at(
+        &self,
+        key: &[u8],
+        snapshot: Option<SnapshotPoint>,
+    ) -> Result<Option<Vec<u8>>, KvError> {
+        Ok(self.get_entry_at(key, snapshot)?.map(|entry| entry.value))
+    }
+
+    /// Retrieves the value of `key` along with its timestamp, sequence number and expiry.
+    pub fn get_entry(&self, key: &[u8]) -> Result<Option<Entry>, KvError> {
+        self.get_entry_at(key, None)
+    }
+
+    /// Retrieves the value of `key` like `KvStore::get_entry`, as of `snapshot` if one is given.
+    fn get_entry_at(
+        &self,
+        key: &[u8],
+        snapshot: Option<SnapshotPoint>,
+    ) -> Result<Option<Entry>, KvError> {
+        let records = match self.version_at(key, snapshot) {
+            Some((entry, operands)) => [operands, &[entry]].concat(),
+            None => return Ok(None),
+        };
+
+        let merge_operator = self.options.merge_operator.as_deref();
+
+        match resolve(&self.readers, &self.dir_path, key, &records, merge_operator)? {
+            Some((value, header)) => Ok(Some(Entry::new(value, &header))),
+            None => Ok(None),
+        }
+    }
+
+    /// The timestamp, sequence number, expiry and size of `key`, from the index alone.
+    pub fn metadata(&self, key: &[u8]) -> Option<Metadata> {
+        self.entry_at(key, None).map(Metadata::from)
+    }
+
+    /// Whether `key` was written after `snapshot` was taken. The snapshot must still be live.
+    pub fn changed_since(&self, key: &[u8], snapshot: u64) -> bool {
+        self.history
+            .get(key)
+            .is_some_and(|versions| versions.iter().any(|(changed, ..)| *changed > snapshot))
+    }
+
+    /// Keeps the entry a write to `key` replaced, and the operands it builds on, as long as a live snapshot may need them.
+    ///
+    /// Must be called before the write touches the operands of `key`.
+    fn remember(&mut self, key: &[u8], replaced: Option<IndexEntry>) {
+        if self.snapshots.is_empty() {
+            return;
+        }
+
+        let operands = self.operands.get(key).cloned().unwrap_or_default();
+
+        self.history
+            .entry(key.to_vec())
+            .or_default()
+            .push((self.seq, replaced, operands));
+    }
+
+    /// Forgets the operands of `key` once a put or delete replaced them. They already count as dead.
+    fn drop_operands(&mut self, key: &[u8]) {
+        self.operands.remove(key);
+    }
+
+    /// Keeps the record a merge into `key` replaced as one of its operands.
+    ///
+    /// The record still has to be read, but counts as dead: compaction folds it away, so it adds to the pressure to compact.
+    fn push_operand(&mut self, key: &[u8], replaced: IndexEntry) {
+        self.mark_dead(&replaced);
+        self.operands
+            .entry(key.to_vec())
+            .or_default()
+            .push(replaced);
+    }
+
+    /// Live and dead bytes of every log file, see `FileStats`.
+    pub fn stats(&self) -> Stats {
+        let now = now_nanos();
+        let files = self
+            .manifest
+            .files()
+            .iter()
+            .map(|file_id| {
+                let stats = self.file_stats.get(file_id).copied().unwrap_or(FileStats {
+                    file_id: *file_id,
+                    ..Default::default()
+                });
+
+                stats.at(now)
+            })
+            .collect();
+
+        Stats {
+            keys: self.memory_store.len(),
+            files,
+        }
+    }
+
+    /// Accounts for a record of `size` bytes appended to `file_id`, which expires at `expires_at` if it is live.
+    fn add_bytes(&mut self, file_id: u64, size: usize, live: bool, expires_at: i64) {
+        let stats = self.file_stats.entry(file_id).or_insert(FileStats {
+            file_id,
+            ..Default::default()
+        });
+
+        match live {
+            true => {
+                stats.live_bytes += size as u64;
+                stats.add_expiring(size as u64, expires_at);
+            }
+            false => stats.dead_bytes += size as u64,
+        }
+    }
+
+    /// Moves a record the index no longer points at from the live to the dead bytes of its file.
+    fn mark_dead(&mut self, entry: &IndexEntry) {
+        if let Some(stats) = self.file_stats.get_mut(&entry.file_id()) {
+            stats.live_bytes = stats.live_bytes.saturating_sub(entry.size as u64);
+            stats.dead_bytes += entry.size as u64;
+
+            if entry.expires_at != NO_EXPIRY {
+                stats.expiring_bytes = stats.expiring_bytes.saturating_sub(entry.size as u64);
+            }
+        }
+    }
+
+    /// Reconstructs the in-memory index by scanning the log files listed in the manifest.
+    ///
+    /// Logs are replayed in manifest order, so newer records always win over older ones.
+    ///
+    /// Immutable logs with a valid `<id>.hint` file are loaded from the hint instead of being read in full.
+    /// Hints are written for the ones that were scanned, so the next startup can skip them too.
+    ///
+    /// A truncated or corrupted final record in the newest log (a torn write) is cut off, so the store opens normally.
+    /// Corruption anywhere else is an error, unless the store was opened with `RecoveryMode::Lenient`.
+    ///
+    /// The active log must already be open on the newest one.
+    ///
+    /// Updates the live and dead bytes of every log as needed.
     fn recovery(&mut self) -> Result<(), KvError> {
-        if !self.dir_path.is_dir() {
-            return Err(KvError::InvalidDir);
-        }
-
-        for log in fs::read_dir(&self.dir_path)? {
-            let log_path = log?.path();
-
-            let mut file = File::open(&log_path)?;
-            let mut offset = 0u64;
-            let file_len = file.metadata()?.len();
-
-            while offset < file_len {
-                // read Header
-                let mut header = [0u8; HEADER_SIZE];
-                file.read_exact(&mut header)?;
-
-                let key_len = u32::from_le_bytes(
-                    header[TYPE_SIZE + TIMESTAMP_SIZE..TYPE_SIZE + TIMESTAMP_SIZE + LEN_SIZE]
-                        .try_into()
-                        .expect("Key size should be 4bytes"),
-                ) as usize;
-
-                let value_len = u32::from_le_bytes(
-                    header[TYPE_SIZE + TIMESTAMP_SIZE + LEN_SIZE
-                        ..TYPE_SIZE + TIMESTAMP_SIZE + LEN_SIZE + LEN_SIZE]
-                        .try_into()
-                        .expect("Value size should be 4bytes"),
-                ) as usize;
-
-                // read Key to update the HashMap
-                let mut key = vec![0u8; key_len];
-                file.read_exact(&mut key)?;
-
-                // total size of this specific record on disk
-                let total_size = HEADER_SIZE + key_len + value_len;
-
-                if header[0] == RecordType::Put as u8 {
-                    // TODO: Clone here is expensive
-                    self.memory_store
-                        .insert(key, (log_path.clone(), offset, total_size));
-                } else {
-                    self.compaction_size += total_size;
-                    self.memory_store.remove(&key);
+        let files = self.manifest.files().to_vec();
+
+        for file_id in files {
+            let is_newest = file_id == self.current_file_id;
+            let log_path = self.log_path(file_id);
+
+            let log_size = match fs::metadata(&log_path) {
+                Ok(metadata) => metadata.len(),
+                // a crash right after rotating can leave the newest log uncreated
+                Err(err) if err.kind() == ErrorKind::NotFound && is_newest => continue,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    return Err(KvError::Corruption {
+                        file: log_path,
+                        offset: 0,
+                    });
                 }
+                Err(err) => return Err(err.into()),
+            };
 
-                offset += total_size as u64;
-                file.seek(SeekFrom::Start(offset))?;
+            // the newest log is still being appended to, so it never has a hint
+            let hint_path = log_path.with_extension("hint");
+
+            if !is_newest && let Some(entries) = read_hint(&hint_path, log_size)? {
+                self.apply_entries(file_id, entries);
+                continue;
             }
 
-            if should_rotate(&log_path) {
-                self.current_file_id += 1;
+            let (entries, valid_size) = self.scan_log(file_id, is_newest)?;
+
+            if is_newest {
+                self.active_entries = entries.clone();
             }
+
+            // a read-only store leaves the torn tail on disk and just ignores it
+            if is_newest
+                && valid_size < log_size
+                && let Some(active) = self.active.as_mut()
+            {
+                active.truncate(valid_size)?;
+            }
+
+            if !is_newest
+                && !self.options.read_only
+                && let Err(err) = write_hint(&hint_path, log_size, &entries)
+            {
+                println!(
+                    "[Warn]: Failed to write hint file {}: {:?}",
+                    hint_path.display(),
+                    err
+                );
+            }
+
+            self.apply_entries(file_id, entries);
         }
 
         Ok(())
     }
 
-    /// Compacts log files by rewriting only the latest key-value pairs to a new log file.
+    /// Applies the records of a single log file, in order, to the in-memory index.
     ///
-    /// Removes obsolete log files and resets the compaction size.
-    fn _compaction(&mut self) -> Result<(), KvError> {
-        if self.rx.recv().is_ok() {
-            println!("[Info]: Starting compaction");
-            let compact_path = self.dir_path.join("compacted.log");
-            let new_file = File::create(&compact_path)?;
-            let active_path = self.dir_path.join(format!("{}.log", self.current_file_id));
+    /// A put that already expired removes its key, like a delete.
+    /// A merge keeps the record it replaces as one of its operands.
+    fn apply_entries(&mut self, file_id: u64, entries: Vec<HintEntry>) {
+        let now = now_nanos();
+
+        for entry in entries {
+            // recovery carries on numbering after the newest write in the logs
+            self.seq = self.seq.max(entry.seq);
+
+            let total_size = entry.size as usize;
+            let is_live = match entry.record_type {
+                RecordType::Put => !is_expired(entry.expires_at, now),
+                RecordType::Merge => true,
+                RecordType::Delete | RecordType::BatchBegin | RecordType::BatchCommit => false,
+            };
+
+            // deleting a missing key changes nothing
+            if !self.snapshots.is_empty() && !entry.record_type.is_marker() {
+                let replaced = self.memory_store.get(&entry.key);
+
+                if is_live || replaced.is_some() {
+                    self.remember(&entry.key, replaced);
+                }
+            }
 
-            for (key, (file, old_offset, ..)) in self.memory_store.iter_mut() {
-                if *file == active_path {
-                    continue;
+            let location = IndexEntry::new(
+                file_id,
+                entry.offset,
+                total_size,
+                entry.timestamp,
+                entry.expires_at,
+                entry.seq,
+            );
+
+            let superseded = match entry.record_type {
+                RecordType::Merge => {
+                    if let Some(replaced) = self.memory_store.put(entry.key.clone(), location) {
+                        self.push_operand(&entry.key, replaced);
+                    }
+                    None
+                }
+                RecordType::Put if is_live => {
+                    self.drop_operands(&entry.key);
+                    self.memory_store.put(entry.key, location)
                 }
+                RecordType::Put | RecordType::Delete => {
+                    self.drop_operands(&entry.key);
+                    self.memory_store.delete(&entry.key)
+                }
+                RecordType::BatchBegin | RecordType::BatchCommit => None,
+            };
 
-                let (value, timestamp) = match read(*old_offset, &file)? {
-                    Some(val) => val,
-                    None => continue,
-                };
+            if let Some(superseded) = superseded {
+                self.mark_dead(&superseded);
+            }
 
-                let converted_time = Duration::from_secs(timestamp as u64);
+            self.add_bytes(file_id, total_size, is_live, entry.expires_at);
+        }
+    }
 
-                let record = Record {
-                    record_type: RecordType::Put,
-                    timestamp: UNIX_EPOCH + converted_time,
-                    key,
-                    value: &value,
+    /// Reads every record of a single log file, returning the location of each one and the size of the valid prefix.
+    ///
+    /// Stops at the first record that can not be read. If that record is the torn tail of the newest log,
+    /// or the store is in lenient mode, the rest of the file is dropped, otherwise the error is returned.
+    /// A batch left without its commit marker is dropped the same way.
+    /// The caller truncates the newest log back to its last good record so new appends follow valid data.
+    ///
+    /// Legacy records are only read from logs written before the manifest, and only before their first versioned record.
+    fn scan_log(&self, file_id: u64, is_newest: bool) -> Result<(Vec<HintEntry>, u64), KvError> {
+        let log_path = &self.log_path(file_id);
+        let file = File::open(log_path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0u64;
+        let mut entries = Vec::new();
+        let mut batch: Option<(u64, Vec<HintEntry>)> = None; // start offset and records of an open batch
+        let mut legacy = self.manifest.is_legacy(file_id);
+
+        while offset < file_len {
+            let remaining = file_len - offset;
+
+            let (header, key) =
+                match self.read_log_entry(&mut reader, log_path, offset, remaining, legacy) {
+                    Ok(entry) => entry,
+                    Err((err, is_tail)) => {
+                        if !(is_newest && is_tail)
+                            && self.options.recovery_mode == RecoveryMode::Strict
+                        {
+                            return Err(err);
+                        }
+
+                        println!(
+                            "[Warn]: Dropping {} bytes from {} at offset {}: {:?}",
+                            remaining,
+                            log_path.display(),
+                            offset,
+                            err
+                        );
+
+                        break;
+                    }
                 };
 
-                // TODO: clone is expensive
-                let (_, offset) = append(record, compact_path.clone())?;
+            legacy &= header.version == 0;
+
+            // total size of this specific record on disk
+            let total_size = header.record_size() as u64;
+
+            let entry = HintEntry {
+                record_type: header.record_type,
+                timestamp: header.timestamp,
+                expires_at: header.expires_at,
+                seq: header.seq,
+                offset,
+                size: total_size,
+                key,
+            };
+
+            match (header.record_type, &mut batch) {
+                (RecordType::BatchBegin, _) => batch = Some((offset, vec![entry])),
+                (RecordType::BatchCommit, Some((_, records))) => {
+                    records.push(entry);
+                    entries.append(records);
+                    batch = None;
+                }
+                (_, Some((_, records))) => records.push(entry),
+                (_, None) => entries.push(entry),
+            }
+
+            offset += total_size;
+        }
+
+        if let Some((start, records)) = batch {
+            println!(
+                "[Warn]: Dropping uncommitted batch of {} records from {} at offset {}",
+                records.len().saturating_sub(1),
+                log_path.display(),
+                start
+            );
+
+            offset = start;
+        }
+
+        Ok((entries, offset))
+    }
+
+    /// Moves writes on to a new log file, leaving a hint file behind for the one that just filled up.
+    ///
+    /// The hint is built from the entries kept as records were appended, so the log is not read again.
+    fn rotate(&mut self) -> Result<(), KvError> {
+        let log_path = self.log_path(self.current_file_id);
+        let hint_path = log_path.with_extension("hint");
+        let log_size = self.writer()?.size();
+
+        // the hint only speeds up recovery, so failing to write it must not fail the write
+        if let Err(err) = write_hint(&hint_path, log_size, &self.active_entries) {
+            println!(
+                "[Warn]: Failed to write hint file {}: {:?}",
+                hint_path.display(),
+                err
+            );
+        }
+
+        // everything written so far must be durable once the writer moves on, see `KvStore::sync_handle`
+        self.sync()?;
+
+        // the manifest lists the new log before it exists, otherwise it would be removed as an orphan on startup
+        let file_id = self.next_file_id;
+        self.manifest.push(file_id, self.seq)?;
+        self.next_file_id += 1;
+
+        self.active = Some(LogWriter::open(self.log_path(file_id))?);
+        self.current_file_id = file_id;
+        self.active_entries.clear();
+
+        Ok(())
+    }
+
+    /// The writer for the active log, or `KvError::ReadOnly` if the store was opened read-only.
+    fn writer(&mut self) -> Result<&mut LogWriter, KvError> {
+        self.active.as_mut().ok_or(KvError::ReadOnly)
+    }
+
+    /// The path of the log file with the given id.
+    fn log_path(&self, file_id: u64) -> PathBuf {
+        log_path(&self.dir_path, file_id)
+    }
+
+    /// Reads the next record of a log during recovery, with `remaining` bytes left in the file.
+    ///
+    /// On failure, also reports whether the bad record is the last one in the file,
+    /// i.e. it runs past the end of the file or ends exactly at it.
+    ///
+    /// Legacy records are only accepted if `legacy` is set. Otherwise a legacy record type counts as the tail too,
+    /// as it is what the zeros a crash can leave past the last write look like.
+    fn read_log_entry(
+        &self,
+        reader: &mut impl BufRead,
+        log_path: &Path,
+        offset: u64,
+        remaining: u64,
+        legacy: bool,
+    ) -> Result<(Header, Vec<u8>), (KvError, bool)> {
+        let is_eof = |err: &KvError| matches!(err, KvError::Io(err) if err.kind() == ErrorKind::UnexpectedEof);
+
+        let first_byte = reader.fill_buf().ok().and_then(|buf| buf.first().copied());
+
+        if !legacy && first_byte.is_some_and(is_legacy_type) {
+            let err = KvError::Corruption {
+                file: log_path.to_path_buf(),
+                offset,
+            };
+            return Err((err, true));
+        }
+
+        let (header, raw_header) = read_header(reader, log_path, offset).map_err(|err| {
+            let is_tail = is_eof(&err) || remaining < HEADER_SIZE as u64;
+            (err, is_tail)
+        })?;
+
+        let size = header.record_size() as u64;
+
+        if size > remaining {
+            let err = KvError::Corruption {
+                file: log_path.to_path_buf(),
+                offset,
+            };
+            return Err((err, true));
+        }
+
+        let (key, _) =
+            read_payload(reader, &header, &raw_header, log_path, offset).map_err(|err| {
+                let is_tail = is_eof(&err) || size == remaining;
+                (err, is_tail)
+            })?;
+
+        Ok((header, key))
+    }
+
+    /// Picks the files to compact and takes the location of every live record in them.
+    ///
+    /// Only immutable logs with enough garbage are compacted, or all of them if `full` is set,
+    /// into new logs with fresh ids. Returns `None` if there is nothing to compact.
+    ///
+    /// The outputs take the place of the newest input, which is safe for live records as nothing newer
+    /// can sit in between. Tombstones are normally dropped, but one from an input that follows a log left alone
+    /// may still shadow a record in it. Those are carried over, as long as the key is still deleted.
+    /// Expired values are dropped too, and shadow older records the same way a tombstone does.
+    ///
+    /// The records of a merge are folded into a single put, except the ones in the active log, which stay on top of it.
+    /// That is only safe if no log sits between the inputs and the active log, so while any merge builds on
+    /// an older record, every immutable log after the first one with enough garbage is compacted too.
+    ///
+    /// # Errors
+    /// Returns `KvError::ReadOnly` if the store was opened read-only.
+    pub fn plan_compaction(&mut self, full: bool) -> Result<Option<CompactionPlan>, KvError> {
+        if self.active.is_none() {
+            return Err(KvError::ReadOnly);
+        }
+
+        let now = now_nanos();
+        let merging = self.options.merge_operator.is_some();
+        let folding = merging && !self.operands.is_empty(); // some merge chains span more than one record
+        let mut inputs = Vec::new();
+        let mut input_bytes = 0;
+        let mut tombstones = HashMap::new();
+        let mut shadowing = HashSet::new(); // inputs that follow a log left alone
+        let mut skipped_older = false;
+
+        for file_id in self.manifest.files().to_vec() {
+            let stats = self.file_stats.get(&file_id).map(|stats| stats.at(now));
+
+            let compactable = match full {
+                true => file_id != self.current_file_id,
+                false if folding && !inputs.is_empty() => file_id != self.current_file_id,
+                false => stats.is_some_and(|stats| self.is_compactable(&stats)),
+            };
+
+            if !compactable {
+                skipped_older = true;
+                continue;
+            }
 
-                // Check if current compact file size is more than the MAX_LOG_SIZE //
+            if skipped_older {
+                for entry in self.read_entries(file_id)? {
+                    let is_removal = match entry.record_type {
+                        RecordType::Delete => true,
+                        RecordType::Put => is_expired(entry.expires_at, now),
+                        RecordType::Merge | RecordType::BatchBegin | RecordType::BatchCommit => {
+                            false
+                        }
+                    };
+
+                    let is_needed = match self.memory_store.get(&entry.key) {
+                        None => true,
+                        // operands left in the active log may build on the key being missing
+                        Some(location) => merging && location.file_id() == self.current_file_id,
+                    };
+
+                    if is_removal && is_needed {
+                        tombstones.insert(entry.key, (entry.timestamp, entry.seq));
+                    }
+                }
 
-                // ACTIVE_LOG file will never be `0` because for this function to run the size of un-compacted data should be above the threshold
-                *file = self.dir_path.join(format!("{}.log", 0)); // set new file to the 0th index log
-                *old_offset = offset; // new_offset
+                shadowing.insert(file_id);
             }
 
-            new_file.sync_all()?;
+            inputs.push(file_id);
+            input_bytes += stats.as_ref().map_or(0, FileStats::total_bytes);
+        }
+
+        if inputs.is_empty() {
+            return Ok(None);
+        }
+
+        let input_ids: HashSet<u64> = inputs.iter().copied().collect();
+
+        let mut entries = Vec::new();
+        let mut expired = Vec::new();
+
+        for (key, entry) in self.memory_store.iter() {
+            let operands = self.operands.get(&key).map_or(&[][..], Vec::as_slice);
 
-            // Delete all old .log files except the active one
-            for file in fs::read_dir(&self.dir_path)? {
-                let path = file?.path();
+            // the records of the key outside the active log, which always holds the newest ones
+            let records: Vec<IndexEntry> = operands
+                .iter()
+                .chain([&entry])
+                .copied()
+                .take_while(|record| record.file_id() != self.current_file_id)
+                .collect();
 
-                if path == active_path || path == compact_path {
-                    continue;
+            if !records
+                .iter()
+                .any(|record| input_ids.contains(&record.file_id()))
+            {
+                continue;
+            }
+
+            if !entry.is_expired(now) {
+                entries.push((key, records));
+                continue;
+            }
+
+            if shadowing.contains(&entry.file_id()) {
+                tombstones.insert(key.clone(), (entry.timestamp, entry.seq));
+            }
+
+            expired.push((key, entry));
+        }
+
+        Ok(Some(CompactionPlan {
+            dir_path: self.dir_path.clone(),
+            inputs,
+            input_bytes,
+            max_file_size: self.options.max_file_size,
+            entries,
+            expired,
+            merge_operator: self.options.merge_operator.clone(),
+            tombstones: tombstones
+                .into_iter()
+                .map(|(key, (timestamp, seq))| (key, timestamp, seq))
+                .collect(),
+        }))
+    }
+
+    /// Hands out a fresh id for a compaction output file, along with its path.
+    ///
+    /// The file is not in the manifest until it is installed, so a crash before then leaves an orphan
+    /// that recovery removes.
+    pub fn allocate_log(&mut self) -> (u64, PathBuf) {
+        let file_id = self.next_file_id;
+        self.next_file_id += 1;
+
+        (file_id, self.log_path(file_id))
+    }
+
+    /// Reads the location of every record in an immutable log, from its hint file if it has a valid one.
+    fn read_entries(&self, file_id: u64) -> Result<Vec<HintEntry>, KvError> {
+        let log_path = self.log_path(file_id);
+        let log_size = fs::metadata(&log_path)?.len();
+
+        match read_hint(&log_path.with_extension("hint"), log_size)? {
+            Some(entries) => Ok(entries),
+            None => Ok(self.scan_log(file_id, false)?.0),
+        }
+    }
+
+    /// Swaps the output of a compaction in for its input files.
+    ///
+    /// The swap happens by writing the manifest, and the inputs may only be deleted once it is durable.
+    /// If this returns an error the manifest is unchanged, so the outputs can be discarded.
+    ///
+    /// Returns the paths of the inputs, for the caller to delete with `delete_logs` once the store is unlocked.
+    /// Inputs that live snapshots may still read are left out, and deleted once they are released.
+    ///
+    /// Index entries are only moved to the copies if they still point at the records that were copied;
+    /// a key written again or deleted during the copy keeps its newer state. Merges during the copy
+    /// just build on the copy.
+    pub fn install_compaction(
+        &mut self,
+        plan: CompactionPlan,
+        output: CompactionOutput,
+    ) -> Result<Vec<PathBuf>, KvError> {
+        let outputs: Vec<u64> = output.files.iter().map(|file| file.file_id).collect();
+        self.manifest.replace(&plan.inputs, &outputs, self.seq)?;
+
+        for output_file in output.files {
+            for (folded, entry) in output_file.records {
+                let location = IndexEntry::new(
+                    output_file.file_id,
+                    entry.offset,
+                    entry.size as usize,
+                    entry.timestamp,
+                    entry.expires_at,
+                    entry.seq,
+                );
+
+                // a tombstone has nothing to replace
+                let live = !folded.is_empty() && self.replace_folded(&entry.key, &folded, location);
+
+                self.add_bytes(
+                    output_file.file_id,
+                    entry.size as usize,
+                    live,
+                    entry.expires_at,
+                );
+            }
+        }
+
+        // expired values were left behind, unless the key was written again in the meantime
+        for (key, entry) in plan.expired {
+            if self.memory_store.get(&key) == Some(entry) {
+                self.memory_store.delete(&key);
+
+                // snapshots taken before the value expired still see it, as if the next write removed it
+                if !self.snapshots.is_empty() {
+                    let versions = self.history.entry(key).or_default();
+                    versions.push((self.seq + 1, Some(entry), Vec::new()));
                 }
+            }
+        }
 
-                fs::remove_file(path).expect("Should delete file");
+        for file_id in &plan.inputs {
+            self.file_stats.remove(file_id);
+        }
+
+        // live snapshots may still read records the copy left behind, see `KvStore::release_snapshot`
+        let retired = match self.snapshots.is_empty() {
+            true => self.retire_logs(&plan.inputs),
+            false => {
+                self.obsolete.extend_from_slice(&plan.inputs);
+                Vec::new()
             }
+        };
 
-            // Have a structured way of storing compacted data so it can renamed accordingly: compacted.0.log -> 0.log
-            // When the max size cap is reached for a log file, it should be rotated
+        println!("[Info]: Compaction finished");
 
-            // Rename compacted.log to 0.log
-            fs::rename(compact_path, self.dir_path.join(format!("{}.log", 0)))
-                .expect("Should rename successfully");
+        Ok(retired)
+    }
 
-            self.compaction_size = 0;
+    /// Points `key` at the copy compaction folded some of its records into, if those are still its oldest records.
+    ///
+    /// Returns whether the copy is live. It is not if the key was written again or deleted during the copy,
+    /// so the copy is already stale, or if it became the operand of a newer merge.
+    fn replace_folded(&mut self, key: &[u8], folded: &[IndexEntry], location: IndexEntry) -> bool {
+        let entry = match self.memory_store.get(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        let operands = self.operands.get(key).map_or(&[][..], Vec::as_slice);
+        let records = [operands, &[entry]].concat();
+
+        if !records.starts_with(folded) {
+            return false;
         }
 
-        Ok(())
+        // the folded operands already count as dead, and so does the copy if newer operands build on it
+        match records[folded.len()..].split_last() {
+            Some((_, newer_operands)) => {
+                let operands = [&[location], newer_operands].concat();
+                self.operands.insert(key.to_vec(), operands);
+                false
+            }
+            None => {
+                self.operands.remove(key);
+                self.memory_store.put(key.to_vec(), location);
+                self.mark_dead(&entry);
+                true
+            }
+        }
     }
 
-    /// Shutdown the key-value store.
-    pub fn shutdown(&mut self) {
-        self.running = false;
+    /// Deletes logs that are no longer live, see `delete_logs`.
+    fn remove_logs(&mut self, file_ids: &[u64]) {
+        delete_logs(&self.retire_logs(file_ids));
     }
-}
 
-impl DbTraits for KvStore {
-    /// Opens a key-value store at the given directory path, creating it if it doesn't exist.
+    /// Closes the read handles of logs that are no longer live, returning their paths to delete.
+    fn retire_logs(&mut self, file_ids: &[u64]) -> Vec<PathBuf> {
+        file_ids
+            .iter()
+            .map(|file_id| {
+                self.readers.evict(*file_id);
+                self.log_path(*file_id)
+            })
+            .collect()
+    }
+
+    /// Takes the receiving end of the compaction trigger, see `compaction::spawn_worker`.
+    pub fn take_compaction_trigger(&mut self) -> Option<Receiver<()>> {
+        self.rx.take()
+    }
+
+    /// Opens a key-value store like `DbTraits::open`, with the given options.
     ///
-    /// Reconstructs the in-memory index from log files and starts compaction task if needed.
-    fn open(path: impl Into<PathBuf>) -> Result<Self, KvError> {
+    /// # Errors
+    /// Returns `KvError::InvalidDir` if the directory is missing and may not be created,
+    /// `KvError::AlreadyExists` if it already holds logs while `error_if_exists` is set,
+    /// and `KvError::Corruption` if the manifest is invalid or a log it lists is missing.
+    pub fn open_with(path: impl Into<PathBuf>, options: Options) -> Result<Self, KvError> {
         let dir_path = path.into();
 
         if !dir_path.exists() {
+            if !options.create_if_missing || options.read_only {
+                return Err(KvError::InvalidDir);
+            }
+
             std::fs::create_dir_all(&dir_path)?;
         }
 
-        let (tx, rx) = mpsc::channel::<()>();
+        let logs = list_logs(&dir_path)?;
+        let manifest = Manifest::load(&dir_path)?;
+
+        if options.error_if_exists && (!logs.is_empty() || manifest.is_some()) {
+            return Err(KvError::AlreadyExists);
+        }
+
+        let manifest = match manifest {
+            Some(manifest) => manifest,
+            // a new directory, or one from before manifests existed, where logs are replayed in id order
+            None => {
+                let mut files: Vec<u64> = logs.iter().map(|(file_id, _)| *file_id).collect();
+                // only these logs can hold records from before checksums
+                let legacy_logs = files.iter().max().map_or(0, |file_id| file_id + 1);
+
+                if files.is_empty() {
+                    files.push(0);
+                }
+
+                let manifest = Manifest::new(&dir_path, files, legacy_logs);
+                if !options.read_only {
+                    manifest.persist()?;
+                }
+
+                manifest
+            }
+        };
+
+        // logs left out of the manifest belong to a compaction that was cut short, either its unfinished output
+        // or inputs it had already replaced
+        for (file_id, log_path) in &logs {
+            if manifest.contains(*file_id) || options.read_only {
+                continue;
+            }
+
+            println!(
+                "[Info]: Removing {}, which is not in the manifest",
+                log_path.display()
+            );
+
+            remove_log(log_path)?;
+        }
+
+        let next_file_id = logs
+            .iter()
+            .map(|(file_id, _)| *file_id)
+            .chain(manifest.files().iter().copied())
+            .max()
+            .map_or(0, |file_id| file_id + 1);
+
+        // writes go to the newest log, recovery cuts off a torn tail through this writer
+        let current_file_id = *manifest
+            .files()
+            .last()
+            .expect("Manifest should list the active log");
+        let active = match options.read_only {
+            true => None,
+            false => Some(LogWriter::open(
+                dir_path.join(format!("{}.log", current_file_id)),
+            )?),
+        };
+
+        let last_seq = manifest.last_seq();
+        let (tx, rx) = mpsc::sync_channel::<()>(1);
 
         let mut store = KvStore {
-            memory_store: HashMap::new(),
+            memory_store: new_indexer(options.index_type),
             dir_path,
-            compaction_size: 0,
-            current_file_id: 0,
+            file_stats: HashMap::new(),
+            current_file_id,
+            next_file_id,
+            manifest,
+            active,
+            readers: ReaderCache::new(options.max_open_files),
             sender: Arc::new(tx),
-            rx,
+            rx: Some(rx),
             running: true,
+            options,
+            written: 0,
+            synced: 0,
+            last_sync: Instant::now(),
+            seq: last_seq,
+            snapshots: BTreeMap::new(),
+            operands: HashMap::new(),
+            history: BTreeMap::new(),
+            obsolete: Vec::new(),
+            active_entries: Vec::new(),
         };
 
         // re-constructs the in-memory index from log files
@@ -313,80 +1337,805 @@ impl DbTraits for KvStore {
         Ok(store)
     }
 
-    /// Inserts or updates a key-value pair in the store.
-    ///
-    /// Appends a Put record to the log and updates the in-memory index.
-    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), KvError> {
-        let mut active_path = self.dir_path.join(format!("{}.log", self.current_file_id));
+    /// Inserts or updates a key-value pair like `DbTraits::put`, without flushing, see `KvStore::sync_if_due`.
+    pub fn put_deferred(&mut self, key: &[u8], value: &[u8]) -> Result<(), KvError> {
+        self.put_expiring(key, value, None)
+    }
 
-        // should rotate and check file_size (recursively check)
-        // Because on start-up the active file might be 1 but 2.log exists and is already full, but after rotating 1.log you get 2.log which is already full
-        if should_rotate(&active_path) {
-            self.current_file_id += 1;
-            active_path = self.dir_path.join(format!("{}.log", self.current_file_id));
-        }
+    /// Inserts or updates a key-value pair like `KvStore::put_with_ttl`, without flushing.
+    pub fn put_with_ttl_deferred(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<(), KvError> {
+        self.put_expiring(key, value, Some(ttl))
+    }
+
+    /// Inserts or updates a key-value pair that expires once `ttl` has passed, if given.
+    fn put_expiring(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<(), KvError> {
+        let timestamp = SystemTime::now();
+        // a TTL too long to represent never runs out
+        let expires_at = ttl.and_then(|ttl| timestamp.checked_add(ttl));
+        let seq = self.seq + 1;
 
         let record = Record {
             record_type: RecordType::Put,
-            timestamp: SystemTime::now(),
+            timestamp,
+            expires_at,
+            seq,
             key,
             value,
         };
 
-        let (size, offset) = append(record, &active_path)?;
+        let (size, offset) = self.append(record)?;
+        self.seq = seq;
+
+        let location = IndexEntry::new(
+            self.current_file_id,
+            offset,
+            size,
+            system_time_to_nanos(&timestamp),
+            expires_at.as_ref().map_or(NO_EXPIRY, system_time_to_nanos),
+            seq,
+        );
+        let superseded = self.memory_store.put(key.to_vec(), location);
+        self.remember(key, superseded);
+        self.drop_operands(key);
+
+        if let Some(superseded) = superseded {
+            self.mark_dead(&superseded);
+        }
+
+        self.add_bytes(self.current_file_id, size, true, location.expires_at);
+
+        Ok(())
+    }
+
+    /// Deletes a key-value pair like `DbTraits::delete`, without flushing.
+    pub fn delete_deferred(&mut self, key: &[u8]) -> Result<(), KvError> {
+        if self.memory_store.get(key).is_none() {
+            return Ok(()); // Since nothing is affected, returning a unit type is fine
+        }
+
+        let seq = self.seq + 1;
+
+        let record = Record {
+            record_type: RecordType::Delete,
+            timestamp: SystemTime::now(),
+            expires_at: None,
+            seq,
+            key,
+            value: &[], // &[]
+        };
+
+        let (size, ..) = self.append(record)?;
+        self.seq = seq;
 
-        self.memory_store
-            .insert(key.to_vec(), (active_path, offset, size));
+        if let Some(superseded) = self.memory_store.delete(key) {
+            self.remember(key, Some(superseded));
+            self.drop_operands(key);
+            self.mark_dead(&superseded);
+        }
 
-        self.compaction_size += size;
+        // tombstones are only needed until compaction drops the records they shadow
+        self.add_bytes(self.current_file_id, size, false, NO_EXPIRY);
 
         Ok(())
     }
 
-    // TODO: return type should be refactored
-    /// Retrieves the value associated with the given key, if it exists.
+    /// Appends a merge operand for `key` like `KvStore::merge`, without flushing.
+    pub fn merge_deferred(&mut self, key: &[u8], operand: &[u8]) -> Result<(), KvError> {
+        if self.options.merge_operator.is_none() {
+            return Err(KvError::NoMergeOperator);
+        }
+
+        let timestamp = SystemTime::now();
+        let seq = self.seq + 1;
+
+        let record = Record {
+            record_type: RecordType::Merge,
+            timestamp,
+            expires_at: None,
+            seq,
+            key,
+            value: operand,
+        };
+
+        let (size, offset) = self.append(record)?;
+        self.seq = seq;
+
+        let location = IndexEntry::new(
+            self.current_file_id,
+            offset,
+            size,
+            system_time_to_nanos(&timestamp),
+            NO_EXPIRY,
+            seq,
+        );
+        let superseded = self.memory_store.put(key.to_vec(), location);
+        self.remember(key, superseded);
+
+        if let Some(superseded) = superseded {
+            self.push_operand(key, superseded);
+        }
+
+        self.add_bytes(self.current_file_id, size, true, NO_EXPIRY);
+
+        Ok(())
+    }
+
+    /// Writes `new` to `key` (deleting it if `None`), but only if its current value is `expected`
+    /// (`None` meaning the key is missing), without flushing.
+    pub fn compare_and_swap_deferred(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<CasResult, KvError> {
+        let current = self.get(key)?;
+
+        if current.as_deref() != expected {
+            return Ok(CasResult::Failed(current));
+        }
+
+        match new {
+            Some(value) => self.put_deferred(key, value)?,
+            None => self.delete_deferred(key)?,
+        }
+
+        Ok(CasResult::Applied)
+    }
+
+    /// Applies a write batch like `KvStore::write`, without flushing.
     ///
-    /// Reads the value from the log file using the in-memory index.
-    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
-        let (file, offset, ..) = match self.memory_store.get(key) {
-            Some(v) => v,
-            None => return Ok(None),
+    /// The index is only updated once the whole batch, commit marker included, is in the log.
+    pub fn write_deferred(&mut self, batch: &WriteBatch) -> Result<(), KvError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let has_merges = batch
+            .ops
+            .iter()
+            .any(|(record_type, ..)| *record_type == RecordType::Merge);
+
+        if has_merges && self.options.merge_operator.is_none() {
+            return Err(KvError::NoMergeOperator);
+        }
+
+        let timestamp = SystemTime::now();
+        // a batch is a single write, so no snapshot sees half of it
+        let seq = self.seq + 1;
+        let marker = |record_type| Record {
+            record_type,
+            timestamp,
+            expires_at: None,
+            seq,
+            key: &[],
+            value: &[],
         };
 
-        match read(*offset, file)? {
-            Some((value, ..)) => Ok(Some(value)),
-            None => Ok(None),
+        let mut records = Vec::with_capacity(batch.len() + 2);
+        records.push(marker(RecordType::BatchBegin));
+        records.extend(batch.ops.iter().map(|(record_type, key, value)| Record {
+            record_type: *record_type,
+            timestamp,
+            expires_at: None,
+            seq,
+            key,
+            value,
+        }));
+        records.push(marker(RecordType::BatchCommit));
+
+        self.rotate_if_full()?;
+        let written = self.writer()?.append_batch(&records)?;
+        self.appended();
+        self.seq = seq;
+
+        let entries = records
+            .iter()
+            .zip(written)
+            .map(|(record, (size, offset))| HintEntry {
+                record_type: record.record_type,
+                timestamp: system_time_to_nanos(&timestamp),
+                expires_at: NO_EXPIRY,
+                seq,
+                offset,
+                size: size as u64,
+                key: record.key.to_vec(),
+            })
+            .collect::<Vec<_>>();
+
+        self.active_entries.extend_from_slice(&entries);
+        self.apply_entries(self.current_file_id, entries);
+
+        Ok(())
+    }
+
+    /// Inserts or updates a key-value pair that expires once `ttl` has passed.
+    ///
+    /// An expired key reads as missing, and its record is reclaimed by compaction without an explicit delete.
+    /// A `ttl` too long to represent, such as `Duration::MAX`, never expires.
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), KvError> {
+        self.put_with_ttl_deferred(key, value, ttl)?;
+
+        self.sync_if_due()
+    }
+
+    /// Applies a merge operand to the value of `key`, without reading it, see `MergeOperator`.
+    ///
+    /// The operand is only appended to the log, and folded into the value when the key is read.
+    ///
+    /// # Errors
+    /// Returns `KvError::NoMergeOperator` if the store was opened without a merge operator.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> Result<(), KvError> {
+        self.merge_deferred(key, operand)?;
+
+        self.sync_if_due()
+    }
+
+    /// Applies every put, delete and merge in `batch` atomically: after a crash, either all of them are there or none.
+    pub fn write(&mut self, batch: &WriteBatch) -> Result<(), KvError> {
+        self.write_deferred(batch)?;
+
+        self.sync_if_due()
+    }
+
+    /// Appends a record to the active log, rotating it first if it is full.
+    ///
+    /// Flushes according to the sync policy, except for `SyncPolicy::Always`, which is up to the caller.
+    fn append(&mut self, record: Record) -> Result<(usize, u64), KvError> {
+        self.rotate_if_full()?;
+
+        let mut entry = HintEntry::for_record(&record);
+        let (size, offset) = self.writer()?.append(record)?;
+
+        entry.offset = offset;
+        entry.size = size as u64;
+        self.active_entries.push(entry);
+
+        self.appended();
+
+        Ok((size, offset))
+    }
+
+    /// Moves on to a new log if the active one is full. Batches are never split across logs.
+    fn rotate_if_full(&mut self) -> Result<(), KvError> {
+        // recovery leaves the active log on the newest file, so a single rotation is always enough
+        if self.writer()?.size() > self.options.max_file_size {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Counts a write. Flushing it is up to the caller, see `KvStore::sync_due`.
+    fn appended(&mut self) {
+        self.written += 1;
+    }
+
+    /// Whether the sync policy asks for the records written so far to be flushed.
+    pub fn sync_due(&self) -> bool {
+        let unsynced = self.unsynced();
+
+        unsynced > 0
+            && match self.options.sync_policy {
+                SyncPolicy::Always => true,
+                SyncPolicy::EveryN(n) => unsynced >= n,
+                SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+                SyncPolicy::Never => false,
+            }
+    }
+
+    /// Flushes if the sync policy asks for it, for writes made on the store directly.
+    ///
+    /// `KvDB` uses the `_deferred` writes instead and flushes afterwards, outside the store lock,
+    /// so concurrent writers can share one fsync.
+    fn sync_if_due(&mut self) -> Result<(), KvError> {
+        if self.sync_due() {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes every record written so far to disk.
+    pub fn sync(&mut self) -> Result<(), KvError> {
+        if let Some(active) = &self.active {
+            active.sync()?;
         }
+
+        self.synced = self.written;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    /// Returns a handle to the active log and the number of records written so far,
+    /// so the log can be flushed without holding on to the store. See `GroupCommit`.
+    pub fn sync_handle(&self) -> (Option<Arc<File>>, u64) {
+        (self.active.as_ref().map(LogWriter::handle), self.written)
+    }
+
+    /// Records that the first `written` records were flushed through a `KvStore::sync_handle`.
+    pub fn mark_synced(&mut self, written: u64) {
+        self.synced = self.synced.max(written);
+        self.last_sync = Instant::now();
+    }
+
+    /// The number of records written since the store was opened.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// The number of records written but not flushed to disk yet.
+    pub fn unsynced(&self) -> u64 {
+        self.written - self.synced
+    }
+
+    /// When appended records are flushed to disk.
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.options.sync_policy
+    }
+
+    /// Shutdown the key-value store.
+    pub fn shutdown(&mut self) {
+        self.running = false;
+
+        // wake up the compaction worker so it notices, unless a wake-up is already pending
+        let _ = self.sender.try_send(());
+    }
+}
+
+impl DbTraits for KvStore {
+    /// Opens a key-value store at the given directory path, creating it if it doesn't exist.
+    ///
+    /// Reconstructs the in-memory index from log files and starts compaction task if needed.
+    fn open(path: impl Into<PathBuf>) -> Result<Self, KvError> {
+        Self::open_with(path, Options::default())
+    }
+
+    /// Inserts or updates a key-value pair in the store.
+    ///
+    /// Appends a Put record to the log and updates the in-memory index.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), KvError> {
+        self.put_deferred(key, value)?;
+
+        self.sync_if_due()
+    }
+
+    /// Retrieves the value associated with the given key, if it exists.
+    ///
+    /// Reads the value from the log file using the in-memory index. See `KvStore::get_entry` for its metadata.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        self.get_at(key, None)
     }
 
     /// Deletes a key-value pair from the store.
     ///
     /// Appends a Delete record to the log and removes the key from the in-memory index.
     fn delete(&mut self, key: &[u8]) -> Result<(), KvError> {
-        if !self.memory_store.contains_key(key) {
-            return Ok(()); // Since nothing is affected, returning a unit type is fine
+        self.delete_deferred(key)?;
+
+        self.sync_if_due()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{helper::nanos_to_system_time, merge::U64Add};
+
+    /// A fresh directory for a test, cleared if an earlier run left it behind.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kv_db_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Appends raw bytes to a file, as a crash or a bad disk would leave them.
+    pub(crate) fn append_raw(path: &Path, bytes: &[u8]) {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .expect("Log should exist")
+            .write_all(bytes)
+            .expect("Should append to the log");
+    }
+
+    #[test]
+    fn zero_filled_tail_is_dropped() {
+        let dir = temp_dir("zero_filled_tail");
+
+        let mut store = KvStore::open(&dir).unwrap();
+        store.put(b"a", b"1").unwrap();
+        drop(store);
+
+        let log = log_path(&dir, 0);
+        let log_size = fs::metadata(&log).unwrap().len();
+        append_raw(&log, &[0u8; 40]);
+
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.stats().keys, 1);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        drop(store);
+
+        assert_eq!(fs::metadata(&log).unwrap().len(), log_size);
+    }
+
+    /// Overwrites the byte at `offset` in a file with its complement.
+    fn flip_byte(path: &Path, offset: usize) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[offset] = !bytes[offset];
+        fs::write(path, bytes).unwrap();
+    }
+
+    /// Cuts a file down to its first `len` bytes.
+    fn truncate_file(path: &Path, len: u64) {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .expect("File should exist")
+            .set_len(len)
+            .expect("Should truncate the file");
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let dir = temp_dir("torn_tail");
+
+        let mut store = KvStore::open(&dir).unwrap();
+        store.put(b"a", b"1").unwrap();
+        store.put(b"b", b"2").unwrap();
+        drop(store);
+
+        // the second record only made it halfway to disk
+        let log = log_path(&dir, 0);
+        let good_size = HEADER_SIZE as u64 + 2;
+        truncate_file(&log, good_size + 10);
+
+        let mut store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(fs::metadata(&log).unwrap().len(), good_size);
+
+        // new writes follow the last good record
+        store.put(b"c", b"3").unwrap();
+        drop(store);
+
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn corruption_before_the_tail_needs_lenient_mode() {
+        let dir = temp_dir("corrupt_newest");
+
+        let mut store = KvStore::open(&dir).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            store.put(key, b"1").unwrap();
         }
+        drop(store);
+
+        // damage the value of the second record, which is followed by a good one
+        let log = log_path(&dir, 0);
+        let log_size = fs::metadata(&log).unwrap().len();
+        flip_byte(&log, 2 * HEADER_SIZE + 3);
+
+        assert!(matches!(
+            KvStore::open(&dir),
+            Err(KvError::Corruption { offset, .. }) if offset == HEADER_SIZE as u64 + 2
+        ));
+        assert_eq!(fs::metadata(&log).unwrap().len(), log_size);
+
+        let lenient = Options::builder()
+            .recovery_mode(RecoveryMode::Lenient)
+            .build();
+        let store = KvStore::open_with(&dir, lenient).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), None);
+        drop(store);
+
+        // lenient recovery cut the log back to its last good record, so it opens strictly again
+        assert_eq!(fs::metadata(&log).unwrap().len(), HEADER_SIZE as u64 + 2);
+        assert!(KvStore::open(&dir).is_ok());
+    }
 
-        let mut active_path = self.dir_path.join(format!("{}.log", self.current_file_id));
+    #[test]
+    fn corrupted_immutable_log_needs_lenient_mode() {
+        let dir = temp_dir("corrupt_immutable");
+        let options = || Options::builder().max_file_size(HEADER_SIZE as u64 + 2);
 
-        if should_rotate(&active_path) {
-            self.current_file_id += 1;
-            active_path = self.dir_path.join(format!("{}.log", self.current_file_id));
+        let mut store = KvStore::open_with(&dir, options().build()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            store.put(key, b"1").unwrap();
         }
+        drop(store);
+
+        // the last record of a full log is not a torn tail, since writes moved on to the next log
+        let log = log_path(&dir, 0);
+        let log_size = fs::metadata(&log).unwrap().len();
+        flip_byte(&log, 2 * HEADER_SIZE + 3);
+        fs::remove_file(log.with_extension("hint")).unwrap();
+
+        assert!(matches!(
+            KvStore::open_with(&dir, options().build()),
+            Err(KvError::Corruption { offset, .. }) if offset == HEADER_SIZE as u64 + 2
+        ));
+
+        let lenient = options().recovery_mode(RecoveryMode::Lenient).build();
+        let store = KvStore::open_with(&dir, lenient).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"1".to_vec()));
+        drop(store);
+
+        // only the newest log is ever truncated
+        assert_eq!(fs::metadata(&log).unwrap().len(), log_size);
+    }
 
-        let record = Record {
-            record_type: RecordType::Delete,
-            timestamp: SystemTime::now(),
-            key,
-            value: &[], // &[]
+    #[test]
+    fn invalid_hints_fall_back_to_scanning() {
+        let dir = temp_dir("hint_fallback");
+        let options = || {
+            Options::builder()
+                .max_file_size(2 * HEADER_SIZE as u64)
+                .build()
         };
 
-        let (size, ..) = append(record, &active_path)?;
+        let mut store = KvStore::open_with(&dir, options()).unwrap();
+        for key in [b"a", b"b", b"c", b"d", b"e", b"f", b"g"] {
+            store.put(key, key).unwrap();
+        }
+        assert_eq!(store.manifest.files(), [0, 1, 2, 3]);
+        drop(store);
+
+        let hint = |file_id| log_path(&dir, file_id).with_extension("hint");
+        let log_size = |file_id| fs::metadata(log_path(&dir, file_id)).unwrap().len();
 
-        self.memory_store.remove(key);
+        // one hint with a damaged entry, one built for a log of another size, and one that is empty
+        flip_byte(&hint(0), 20);
+        write_hint(&hint(1), log_size(1) + 1, &[]).unwrap();
+        fs::write(hint(2), b"").unwrap();
 
-        self.compaction_size += size;
+        let store = KvStore::open_with(&dir, options()).unwrap();
+        for key in [b"a", b"b", b"c", b"d", b"e", b"f", b"g"] {
+            assert_eq!(store.get(key).unwrap(), Some(key.to_vec()));
+        }
+        drop(store);
 
-        Ok(())
+        // the scans left valid hints behind for the next startup
+        for file_id in 0..3 {
+            let entries = read_hint(&hint(file_id), log_size(file_id)).unwrap();
+            assert_eq!(entries.map(|entries| entries.len()), Some(2));
+        }
+    }
+
+    #[test]
+    fn batch_without_commit_is_dropped() {
+        let dir = temp_dir("torn_batch");
+
+        let mut store = KvStore::open(&dir).unwrap();
+        store.put(b"a", b"1").unwrap();
+        store
+            .write(WriteBatch::new().put(b"b", b"2").delete(b"a"))
+            .unwrap();
+        drop(store);
+
+        // the batch made it to disk, but its commit marker did not
+        let log = log_path(&dir, 0);
+        let log_size = fs::metadata(&log).unwrap().len();
+        truncate_file(&log, log_size - HEADER_SIZE as u64);
+
+        let mut store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(fs::metadata(&log).unwrap().len(), HEADER_SIZE as u64 + 2);
+
+        store
+            .write(WriteBatch::new().put(b"c", b"3").delete(b"a"))
+            .unwrap();
+        drop(store);
+
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn zero_filled_log_is_not_read_as_legacy_records() {
+        let dir = temp_dir("zero_filled_log");
+
+        let mut store = KvStore::open(&dir).unwrap();
+        store.put(b"a", b"1").unwrap();
+        drop(store);
+
+        // a crash right after rotating can leave the new log full of zeros
+        let mut manifest = Manifest::load(&dir).unwrap().unwrap();
+        manifest.push(1, manifest.last_seq()).unwrap();
+        fs::write(log_path(&dir, 1), [0u8; 64]).unwrap();
+
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"").unwrap(), None);
+        assert_eq!(store.stats().keys, 1);
+        drop(store);
+
+        assert_eq!(fs::metadata(log_path(&dir, 1)).unwrap().len(), 0);
+
+        // once another log follows it, the zeros are corruption
+        manifest.push(2, manifest.last_seq()).unwrap();
+        fs::write(log_path(&dir, 1), [0u8; 64]).unwrap();
+
+        assert!(matches!(
+            KvStore::open(&dir),
+            Err(KvError::Corruption { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn logs_from_before_the_manifest_keep_their_legacy_records() {
+        let dir = temp_dir("legacy_logs");
+        fs::create_dir_all(&dir).unwrap();
+
+        // record_type | timestamp | key_size | value_size | key | value
+        let mut legacy = vec![RecordType::Put as u8];
+        legacy.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+        legacy.extend_from_slice(&1u32.to_le_bytes());
+        legacy.extend_from_slice(&1u32.to_le_bytes());
+        legacy.extend_from_slice(b"a1");
+        fs::write(log_path(&dir, 0), legacy).unwrap();
+
+        let mut store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        store.put(b"b", b"2").unwrap();
+        drop(store);
+
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert!(store.manifest.is_legacy(0));
+        assert!(!store.manifest.is_legacy(1));
+    }
+
+    #[test]
+    fn legacy_type_after_versioned_record_is_corruption() {
+        let dir = temp_dir("flipped_version");
+        // two records fill the first log, so it is immutable by the time the third is written
+        let options = || Options::builder().max_file_size(HEADER_SIZE as u64 + 2);
+
+        let mut store = KvStore::open_with(&dir, options().build()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            store.put(key, b"1").unwrap();
+        }
+        drop(store);
+
+        // flip the version byte of the second record, and make recovery scan the log
+        let log = log_path(&dir, 0);
+        let mut bytes = fs::read(&log).unwrap();
+        bytes[HEADER_SIZE + 2] = 0;
+        fs::write(&log, bytes).unwrap();
+        fs::remove_file(log.with_extension("hint")).unwrap();
+
+        assert!(matches!(
+            KvStore::open_with(&dir, options().build()),
+            Err(KvError::Corruption { offset, .. }) if offset == HEADER_SIZE as u64 + 2
+        ));
+
+        let lenient = options().recovery_mode(RecoveryMode::Lenient).build();
+        let store = KvStore::open_with(&dir, lenient).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn rotation_hint_matches_log() {
+        let dir = temp_dir("rotation_hint");
+        let options = || Options::builder().max_file_size(200).build();
+
+        let mut store = KvStore::open_with(&dir, options()).unwrap();
+        store.put(b"a", b"1").unwrap();
+        store
+            .put_with_ttl(b"b", b"2", Duration::from_secs(60))
+            .unwrap();
+        drop(store);
+
+        // the entries written before reopening come from recovery, the rest from the writes themselves
+        let mut store = KvStore::open_with(&dir, options()).unwrap();
+        store
+            .write(WriteBatch::new().put(b"c", b"3").delete(b"a"))
+            .unwrap();
+        store.put(b"d", b"4").unwrap();
+        assert_eq!(store.manifest.files(), [0, 1]);
+
+        let log = log_path(&dir, 0);
+        let log_size = fs::metadata(&log).unwrap().len();
+        let hint = read_hint(&log.with_extension("hint"), log_size).unwrap();
+        let (scanned, _) = store.scan_log(0, false).unwrap();
+
+        assert_eq!(format!("{:?}", hint), format!("{:?}", Some(scanned)));
+    }
+
+    #[test]
+    fn merge_operator_alone_keeps_compaction_selective() {
+        let dir = temp_dir("selective_with_merge_operator");
+        let options = Options::builder()
+            .max_file_size(2 * HEADER_SIZE as u64)
+            .compaction_garbage_ratio(0.4)
+            .merge_operator(U64Add)
+            .build();
+        let mut store = KvStore::open_with(&dir, options).unwrap();
+
+        // only the first log holds garbage
+        for key in [b"a", b"a", b"b", b"c", b"d", b"e", b"f"] {
+            store.put(key, b"1").unwrap();
+        }
+        assert_eq!(store.manifest.files(), [0, 1, 2, 3]);
+
+        let plan = store.plan_compaction(false).unwrap().unwrap();
+        assert_eq!(plan.inputs, [0]);
+
+        // a merge building on an older record has to be folded with everything after it
+        store.merge(b"g", &1u64.to_le_bytes()).unwrap();
+        store.merge(b"g", &1u64.to_le_bytes()).unwrap();
+
+        let files = store.manifest.files().to_vec();
+        let plan = store.plan_compaction(false).unwrap().unwrap();
+        assert_eq!(plan.inputs, files[..files.len() - 1]);
+    }
+
+    #[test]
+    fn unrepresentable_ttl_never_expires() {
+        let dir = temp_dir("unrepresentable_ttl");
+
+        let mut store = KvStore::open(&dir).unwrap();
+        store.put_with_ttl(b"a", b"1", Duration::MAX).unwrap();
+        assert_eq!(store.metadata(b"a").unwrap().expires_at, None);
+        drop(store);
+
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn expiry_past_nanosecond_range_saturates() {
+        let dir = temp_dir("expiry_past_nanos");
+        let ttl = Duration::from_secs(300 * 365 * 24 * 60 * 60);
+
+        let mut store = KvStore::open(&dir).unwrap();
+        store.put_with_ttl(b"a", b"1", ttl).unwrap();
+        drop(store);
+
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(
+            store.metadata(b"a").unwrap().expires_at,
+            Some(nanos_to_system_time(i64::MAX))
+        );
+    }
+
+    #[test]
+    fn compaction_trigger_holds_one_wake_up() {
+        let mut store = KvStore::open(temp_dir("compaction_trigger")).unwrap();
+        let trigger = store.take_compaction_trigger().unwrap();
+
+        for _ in 0..100 {
+            let _ = store.sender.try_send(());
+        }
+        store.shutdown();
+
+        assert!(trigger.try_recv().is_ok());
+        assert!(trigger.try_recv().is_err());
     }
 }
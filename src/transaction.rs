@@ -0,0 +1,217 @@
+//! Optimistic transactions, see `KvDB::begin_transaction`.
+//!
+//! A transaction reads from a snapshot and keeps its writes in memory, so it never blocks other writers.
+//! On commit, the writes are applied as one `WriteBatch`, but only if no key the transaction read or wrote
+//! was written by anyone else since it began. Otherwise the commit fails with `KvError::Conflict`
+//! and the caller can retry the whole transaction.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{batch::WriteBatch, db::KvDB, error::KvError, snapshot::Snapshot};
+
+/// A set of reads and writes that commits all at once, or not at all if it conflicts with another writer.
+///
+/// Dropping a transaction without committing it rolls it back.
+///
+/// ```no_run
+/// use kv_db::{db::KvDB, store::DbTraits};
+///
+/// let db = KvDB::open("tmp").expect("Should open the database");
+///
+/// let mut tx = db.begin_transaction();
+/// let balance = tx.get(b"balance").expect("Should read the balance");
+/// tx.put(b"balance", &[balance.map_or(0, |value| value[0]) + 1]);
+///
+/// tx.commit().expect("Nobody else should have touched the balance");
+/// ```
+pub struct Transaction {
+    db: KvDB,
+    snapshot: Snapshot,
+    reads: BTreeSet<Vec<u8>>,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>, // `None` deletes the key
+}
+
+impl Transaction {
+    pub(crate) fn new(db: KvDB, snapshot: Snapshot) -> Self {
+        Self {
+            db,
+            snapshot,
+            reads: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Retrieves the value of `key`, as written by this transaction or as of its snapshot.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+
+        self.reads.insert(key.to_vec());
+
+        self.snapshot.get(key)
+    }
+
+    /// Inserts or updates a key-value pair when the transaction commits.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    /// Deletes a key when the transaction commits.
+    pub fn delete(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    /// Applies every write of the transaction atomically.
+    ///
+    /// # Errors
+    /// Returns `KvError::Conflict` if a key the transaction read or wrote was written by someone else
+    /// since it began. Nothing is written then.
+    pub fn commit(self) -> Result<(), KvError> {
+        let mut batch = WriteBatch::new();
+
+        for (key, value) in &self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+
+        let keys = self
+            .reads
+            .iter()
+            .chain(self.writes.keys())
+            .map(Vec::as_slice);
+
+        self.db.write_unchanged(&batch, keys, &self.snapshot)
+    }
+
+    /// Discards every write of the transaction.
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        options::Options,
+        store::{DbTraits, tests::temp_dir},
+    };
+
+    #[test]
+    fn read_key_written_by_someone_else_conflicts() {
+        let mut db = KvDB::open(temp_dir("tx_read_write")).unwrap();
+        db.put(b"balance", b"1").unwrap();
+
+        let mut tx = db.begin_transaction();
+        assert_eq!(tx.get(b"balance").unwrap(), Some(b"1".to_vec()));
+        tx.put(b"audit", b"read 1");
+
+        db.put(b"balance", b"2").unwrap();
+
+        assert!(matches!(tx.commit(), Err(KvError::Conflict { key }) if key == b"balance"));
+        assert_eq!(db.get(b"audit").unwrap(), None);
+    }
+
+    #[test]
+    fn new_key_written_by_someone_else_conflicts() {
+        let db = KvDB::open(temp_dir("tx_write_write")).unwrap();
+
+        let mut first = db.begin_transaction();
+        let mut second = db.begin_transaction();
+        first.put(b"new", b"first");
+        second.put(b"new", b"second");
+
+        first.commit().unwrap();
+
+        assert!(matches!(second.commit(), Err(KvError::Conflict { key }) if key == b"new"));
+        assert_eq!(db.get(b"new").unwrap(), Some(b"first".to_vec()));
+    }
+
+    #[test]
+    fn transaction_reads_its_own_writes() {
+        let mut db = KvDB::open(temp_dir("tx_own_writes")).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let mut tx = db.begin_transaction();
+        tx.put(b"a", b"2");
+        tx.put(b"b", b"3");
+        assert_eq!(tx.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(tx.get(b"b").unwrap(), Some(b"3".to_vec()));
+
+        tx.delete(b"a");
+        assert_eq!(tx.get(b"a").unwrap(), None);
+
+        // nothing is visible outside before the commit
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+
+        tx.commit().unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn releasing_other_snapshots_keeps_conflicts() {
+        let mut db = KvDB::open(temp_dir("tx_released_snapshots")).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let older = db.snapshot();
+        let mut tx = db.begin_transaction();
+        let newer = db.snapshot();
+        tx.get(b"a").unwrap();
+        tx.put(b"b", b"1");
+
+        db.put(b"a", b"2").unwrap();
+
+        // the write to `a` is still newer than the transaction, whatever else is released
+        drop(older);
+        drop(newer);
+        assert!(matches!(tx.commit(), Err(KvError::Conflict { key }) if key == b"a"));
+
+        // and once every snapshot is gone, an untouched transaction commits
+        let mut tx = db.begin_transaction();
+        let other = db.snapshot();
+        db.put(b"c", b"1").unwrap();
+        drop(other);
+
+        tx.get(b"a").unwrap();
+        tx.put(b"b", b"2");
+        tx.commit().unwrap();
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn rollback_leaves_nothing_behind() {
+        let dir = temp_dir("tx_rollback");
+        let options = Options::builder().max_file_size(128).build();
+        let mut db = KvDB::open_with(&dir, options).unwrap();
+
+        for i in 0..10u32 {
+            db.put(b"a", &i.to_le_bytes()).unwrap();
+        }
+
+        let mut tx = db.begin_transaction();
+        tx.put(b"b", b"1");
+        tx.rollback();
+
+        assert_eq!(db.get(b"b").unwrap(), None);
+
+        // the snapshot of the transaction is gone, so compacted logs are deleted right away
+        let log_count = || {
+            fs::read_dir(&dir)
+                .unwrap()
+                .filter(|file| file.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+                .count()
+        };
+        let before = log_count();
+        let report = db.compact().unwrap();
+
+        assert_eq!(
+            log_count(),
+            before - report.files_read + report.files_written
+        );
+    }
+}
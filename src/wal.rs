@@ -1,10 +1,319 @@
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{ErrorKind, IoSlice, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+};
 
-const MAX_LOG_SIZE: u64 = 5 * 1024 * 1024; // 1GB
+use crate::{error::KvError, record::Record};
 
-/// a function to check when to rotate the file_id
-pub fn should_rotate(active_path: &PathBuf) -> bool {
-    let current_size = std::fs::metadata(active_path).map_or(0, |m| m.len());
+/// An open log file that records are appended to.
+///
+/// The size of the file is tracked in memory, so appending never has to ask the filesystem for it.
+#[derive(Debug)]
+pub struct LogWriter {
+    path: PathBuf,
+    file: Arc<File>,
+    size: u64,
+}
+
+impl LogWriter {
+    /// Opens the log file at `path` for appending, creating it if it doesn't exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, KvError> {
+        let path = path.into();
+        let file = File::options().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file: Arc::new(file),
+            size,
+        })
+    }
+
+    /// Creates an empty log file at `path`, truncating it if it already exists.
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self, KvError> {
+        let path = path.into();
+        let file = File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)?;
+
+        Ok(Self {
+            path,
+            file: Arc::new(file),
+            size: 0,
+        })
+    }
+
+    /// Appends a record to the log file.
+    ///
+    /// The record is not flushed to disk, see `LogWriter::sync`.
+    /// If the write fails, the log is cut back so a partial record is not followed by later ones.
+    ///
+    /// # Arguments
+    /// * `record` - The record to append (Put/Delete).
+    ///
+    /// # Returns
+    /// Returns the number of bytes written and the offset at which the record was written.
+    pub fn append(&mut self, record: Record) -> Result<(usize, u64), KvError> {
+        let header = record.encode_header();
+
+        let offset = self.size;
+
+        // buffer contents: header (version, checksum, record_type, timestamp, key_size, value_size) | key n-bytes | value n-bytes
+        let mut bufs = [
+            IoSlice::new(&header),
+            IoSlice::new(record.key),
+            IoSlice::new(record.value), // Would be empty for Delete
+        ];
+        let size = header.len() + record.key.len() + record.value.len();
+
+        if let Err(err) = write_all_vectored(&self.file, &mut bufs) {
+            self.cut_back();
+            return Err(err);
+        }
+
+        self.size += size as u64;
+
+        Ok((size, offset))
+    }
+
+    /// Appends several records as one write, see `LogWriter::append`.
+    ///
+    /// If the write fails, the log is cut back so a partial batch is not followed by later records.
+    ///
+    /// # Returns
+    /// Returns the number of bytes written and the offset of each record.
+    pub fn append_batch(&mut self, records: &[Record]) -> Result<Vec<(usize, u64)>, KvError> {
+        let headers: Vec<_> = records.iter().map(Record::encode_header).collect();
+
+        let mut bufs = Vec::with_capacity(records.len() * 3);
+        let mut written = Vec::with_capacity(records.len());
+        let mut offset = self.size;
+
+        for (header, record) in headers.iter().zip(records) {
+            bufs.extend([
+                IoSlice::new(header),
+                IoSlice::new(record.key),
+                IoSlice::new(record.value),
+            ]);
+
+            let size = header.len() + record.key.len() + record.value.len();
+            written.push((size, offset));
+            offset += size as u64;
+        }
+
+        if let Err(err) = write_all_vectored(&self.file, &mut bufs) {
+            self.cut_back();
+            return Err(err);
+        }
+
+        self.size = offset;
+
+        Ok(written)
+    }
+
+    /// Drops whatever a failed write left past the tracked size, and moves the file position back to it
+    /// for logs that were not opened for appending.
+    ///
+    /// This is best effort: if it fails too, recovery treats the leftover bytes as a torn tail.
+    fn cut_back(&self) {
+        let _ = self.file.set_len(self.size);
+        let _ = (&*self.file).seek(SeekFrom::Start(self.size));
+    }
+
+    /// Cuts the log file back to `size` bytes, dropping everything after it.
+    pub fn truncate(&mut self, size: u64) -> Result<(), KvError> {
+        self.file.set_len(size)?;
+        self.file.sync_all()?;
+        self.size = size;
+
+        Ok(())
+    }
+
+    /// Flushes the log file to disk.
+    pub fn sync(&self) -> Result<(), KvError> {
+        self.file.sync_all()?;
+
+        Ok(())
+    }
+
+    /// A shared handle to the log file, so it can be flushed without holding on to the writer.
+    pub fn handle(&self) -> Arc<File> {
+        Arc::clone(&self.file)
+    }
+
+    /// The current size of the log file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Writes every buffer in `bufs`, retrying until the whole record is on the file.
+fn write_all_vectored(mut file: &File, mut bufs: &mut [IoSlice<'_>]) -> Result<(), KvError> {
+    IoSlice::advance_slices(&mut bufs, 0); // skip leading empty buffers
+
+    while !bufs.is_empty() {
+        match file.write_vectored(bufs) {
+            Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero).into()),
+            Ok(written) => IoSlice::advance_slices(&mut bufs, written),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Lets concurrent writers share a single fsync.
+///
+/// Every write is numbered. A writer that needs its write to be durable calls `GroupCommit::wait` with that number:
+/// one writer becomes the leader and flushes everything written so far, while the others wait for it,
+/// so a burst of writers pays for one fsync instead of one each.
+#[derive(Debug, Default)]
+pub struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    synced: u64, // every write up to this number is durable
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blocks until write number `ticket` is durable.
+    ///
+    /// If no flush is in progress, the caller becomes the leader and runs `sync`,
+    /// which must flush the log and return the number of the last write it covered.
+    pub fn wait(
+        &self,
+        ticket: u64,
+        sync: impl FnOnce() -> Result<u64, KvError>,
+    ) -> Result<(), KvError> {
+        let mut state = self.lock();
+
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+
+            if !state.syncing {
+                break;
+            }
+
+            state = self
+                .synced
+                .wait(state)
+                .expect("Group commit lock should not be poisoned");
+        }
+
+        state.syncing = true;
+        drop(state);
+
+        let result = sync();
+
+        let mut state = self.lock();
+        state.syncing = false;
+
+        if let Ok(synced) = result {
+            state.synced = state.synced.max(synced);
+        }
+
+        // on failure, a waiting writer takes over as leader and retries
+        self.synced.notify_all();
+
+        result.map(|_| ())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CommitState> {
+        self.state
+            .lock()
+            .expect("Group commit lock should not be poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process::Command, time::SystemTime};
+
+    use super::*;
+    use crate::{
+        record::{HEADER_SIZE, RecordType},
+        store::tests::temp_dir,
+    };
+
+    /// Set in the child process that runs a test under a file size limit.
+    const FSIZE_CHILD: &str = "KV_DB_FSIZE_CHILD";
+
+    fn record<'a>(key: &'a [u8], value: &'a [u8]) -> Record<'a> {
+        Record {
+            record_type: RecordType::Put,
+            timestamp: SystemTime::now(),
+            expires_at: None,
+            seq: 0,
+            key,
+            value,
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn failed_append_is_cut_back() {
+        // runs itself again with a small file size limit, ignoring SIGXFSZ so a write past the limit is cut short
+        // and the next one fails with EFBIG, like a disk filling up mid-record
+        if env::var_os(FSIZE_CHILD).is_none() {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg("trap '' XFSZ; ulimit -f 1; exec \"$0\" --exact wal::tests::failed_append_is_cut_back")
+                .arg(env::current_exe().unwrap())
+                .env(FSIZE_CHILD, "1")
+                .status()
+                .unwrap();
+
+            assert!(status.success());
+            return;
+        }
+
+        let dir = temp_dir("failed_append");
+        fs::create_dir_all(&dir).unwrap();
+
+        // an appending log, like the active one, and a created one, like compaction output
+        check_cut_back(LogWriter::open(dir.join("0.log")).unwrap());
+        check_cut_back(LogWriter::create(dir.join("1.log")).unwrap());
+    }
+
+    /// Appends to `writer` until the file size limit is hit, then checks the next record follows the last good one.
+    fn check_cut_back(mut writer: LogWriter) {
+        let path = writer.path().to_path_buf();
+        let value = [7u8; 300 - HEADER_SIZE - 1];
+
+        let failed_at = (0..10)
+            .map(|_| (writer.size(), writer.append(record(b"a", &value))))
+            .find_map(|(size, appended)| appended.is_err().then_some(size))
+            .expect("An append should run into the file size limit");
+
+        assert_eq!(writer.size(), failed_at);
+        assert_eq!(fs::metadata(&path).unwrap().len(), failed_at);
+
+        // the next record lands where the index will look for it
+        let (size, offset) = writer.append(record(b"b", b"1")).unwrap();
+        let bytes = fs::read(&path).unwrap();
 
-    current_size > MAX_LOG_SIZE
+        assert_eq!(offset, failed_at);
+        assert_eq!(bytes.len() as u64, offset + size as u64);
+        assert_eq!(bytes[offset as usize + HEADER_SIZE], b'b');
+    }
 }